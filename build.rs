@@ -1,56 +1,77 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+// fnv-1a over the shader and every module it can import. mtimes aren't enough, a checkout
+// gives the sources and the committed spv whatever order git happens to write them in
+fn source_hash(path: &Path, modules: &[PathBuf]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in std::iter::once(path).chain(modules.iter().map(|m| m.as_path())) {
+        for byte in std::fs::read(file).unwrap_or_default() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{hash:016x}")
+}
 
-fn compile_shader(slangc: &str, path: &Path, stem: &str, stage: &str, out_path: &Path) {
+fn compile_shader(slangc: &str, path: &Path, modules: &[PathBuf], stem: &str, stage: &str, out_path: &Path) {
     println!("cargo:rerun-if-changed={}", path.display());
 
-    let src_modified = path.metadata().and_then(|m| m.modified()).ok();
-    let out_modified = out_path.metadata().and_then(|m| m.modified()).ok();
-
-    let needs_compile = match (src_modified, out_modified) {
-        (Some(src), Some(out)) => src > out,
-        _ => !out_path.exists(),
-    };
+    // the hash of the sources the spv was compiled from is kept next to it
+    let hash_path = out_path.with_extension("spv.hash");
+    let hash = source_hash(path, modules);
+    let built_from = std::fs::read_to_string(&hash_path).ok();
+    if out_path.exists() && built_from.as_deref().map(str::trim) == Some(hash.as_str()) {
+        return;
+    }
 
-    if needs_compile {
-        println!("cargo:warning=compiling {stem}.slang -> compiled/{stem}.spv");
-        let status = std::process::Command::new(slangc)
-            .arg(path)
-            .arg("-o")
-            .arg(out_path)
-            .arg("-target")
-            .arg("spirv")
-            .arg("-entry")
-            .arg("main")
-            .arg("-stage")
-            .arg(stage)
-            .status()
-            .expect("failed to run slangc; set SLANGC env var if not in PATH");
-
-        if !status.success() {
-            panic!("slangc failed for {stem}.slang");
-        }
+    println!("cargo:warning=compiling {stem}.slang -> compiled/{stem}.spv");
+    let status = std::process::Command::new(slangc)
+        .arg(path)
+        .arg("-o")
+        .arg(out_path)
+        .arg("-target")
+        .arg("spirv")
+        .arg("-entry")
+        .arg("main")
+        .arg("-stage")
+        .arg(stage)
+        .status()
+        .unwrap_or_else(|e| panic!("compiled/{stem}.spv is out of date and slangc failed to run ({e}); set SLANGC env var if not in PATH"));
+
+    if !status.success() {
+        panic!("slangc failed for {stem}.slang");
     }
+    std::fs::write(&hash_path, hash).unwrap();
+}
+
+fn slang_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries.map(|e| e.unwrap().path()).filter(|p| p.extension().and_then(|e| e.to_str()) == Some("slang")).collect();
+    files.sort();
+    files
 }
 
 fn main() {
     let shaders_dir = Path::new("shaders");
     let out_dir = shaders_dir.join("compiled");
+    let mesh_dir = shaders_dir.join("mesh");
 
     std::fs::create_dir_all(&out_dir).unwrap();
 
     let slangc = std::env::var("SLANGC").unwrap_or_else(|_| "slangc".to_string());
 
-    let module_path = shaders_dir.join("sgpu.slang");
-    println!("cargo:rerun-if-changed={}", module_path.display());
-
-    for entry in std::fs::read_dir(shaders_dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-
-        if path.extension().and_then(|e| e.to_str()) != Some("slang") {
-            continue;
-        }
+    // imported by the root shaders, a change to one of them recompiles all of them
+    let modules: Vec<PathBuf> = ["sgpu", "common"].iter().map(|m| shaders_dir.join(format!("{m}.slang"))).collect();
+    for module in &modules {
+        println!("cargo:rerun-if-changed={}", module.display());
+    }
+    // new shaders show up as a change to their directory
+    println!("cargo:rerun-if-changed={}", shaders_dir.display());
+    println!("cargo:rerun-if-changed={}", mesh_dir.display());
 
+    for path in slang_files(shaders_dir) {
         let stem = path.file_stem().unwrap().to_str().unwrap();
         if stem == "sgpu" || stem == "common" {
             continue;
@@ -62,32 +83,22 @@ fn main() {
         };
 
         let out_path = out_dir.join(format!("{stem}.spv"));
-        compile_shader(&slangc, &path, stem, stage, &out_path);
+        compile_shader(&slangc, &path, &modules, stem, stage, &out_path);
     }
 
-    let mesh_dir = shaders_dir.join("mesh");
-    if mesh_dir.exists() {
-        for entry in std::fs::read_dir(&mesh_dir).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-
-            if path.extension().and_then(|e| e.to_str()) != Some("slang") {
-                continue;
-            }
-
-            let stem = path.file_stem().unwrap().to_str().unwrap();
-            if stem == "sgpu" || stem == "common" {
-                continue;
-            }
-
-            let stage = match stem {
-                "vert" => "vertex",
-                "frag" => "fragment",
-                _ => panic!("unknown shader stage for mesh shader {stem}"),
-            };
-
-            let out_path = out_dir.join(format!("{stem}.spv"));
-            compile_shader(&slangc, &path, stem, stage, &out_path);
+    for path in slang_files(&mesh_dir) {
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        if stem == "sgpu" || stem == "common" {
+            continue;
         }
+
+        let stage = match stem {
+            "vert" => "vertex",
+            "frag" => "fragment",
+            _ => panic!("unknown shader stage for mesh shader {stem}"),
+        };
+
+        let out_path = out_dir.join(format!("{stem}.spv"));
+        compile_shader(&slangc, &path, &modules, stem, stage, &out_path);
     }
 }
@@ -1,30 +1,69 @@
 use glam::vec3;
 use sgpu::*;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
 use winit::{
     dpi::PhysicalSize,
-    event::{DeviceEvent, WindowEvent},
+    event::{DeviceEvent, MouseButton, WindowEvent},
+    keyboard::KeyCode,
     window::Window,
 };
 
 use crate::camera::Camera;
-use crate::chunk::Face;
+use crate::chunk::{Block, BlockRegistry, Face, Generator, MeshingMode, SEED, TerrainConfig, create_generator};
 use crate::renderer::*;
 use crate::world::*;
 
 use super::input::InputManager;
 
-const MAX_FACES: usize = 8000000;
-const MAX_COMMANDS: usize = 20000;
+// the face buffer starts at INITIAL_FACES and doubles when full, up to MAX_FACES
+const INITIAL_FACES: usize = 2000000;
+const MAX_FACES: usize = 32000000;
+// compaction starts once the free space is this fragmented, and moves at most this much per frame
+const COMPACT_FRAGMENTATION: f64 = 0.5;
+const COMPACT_BYTES_PER_FRAME: u64 = 4 * 1024 * 1024;
+// meshes a compaction pass looks at per frame, moved or not, so a pass over a big buffer is spread over several frames
+const COMPACT_VISITS_PER_FRAME: usize = 512;
+// after a pass that couldn't move anything, frames to wait before checking the fragmentation again
+const COMPACT_RETRY_FRAMES: u32 = 600;
+// meshes are written into a ring of STAGING_BYTES and copied to the face buffer from there,
+// about UPLOAD_BYTES_PER_FRAME each frame. the rest waits for the next one
+const STAGING_BYTES: usize = 32 * 1024 * 1024;
+const UPLOAD_BYTES_PER_FRAME: usize = 8 * 1024 * 1024;
+// the draw command buffer starts with room for this many chunks and doubles when full
+const INITIAL_COMMANDS: usize = 4096;
 const GENERATION_RADIUS: u32 = 32;
 const UNLOAD_RADIUS: u32 = 34;
-const NUM_WORKERS: usize = 8;
-const SEED: u32 = 69;
+// chunks only kept around as meshing neighbours are evicted past this
+const CHUNK_CACHE_BYTES: usize = 256 * 1024 * 1024;
+// None uses one worker per core
+const NUM_WORKERS: Option<usize> = None;
+const MESHING_MODE: MeshingMode = MeshingMode::Greedy;
+pub const BLOCKS_PATH: &str = "assets/blocks.ron";
+// every terrain and seed gets its own directory in here, edits only make sense on the world they were made in
+const SAVES_DIR: &str = "saves";
+const PLACE_BLOCK: &str = "stone";
+const REACH: f32 = 8.0;
+const STATS_KEY: KeyCode = KeyCode::F3;
+
+// the terrain picked on the command line and its name, the built in default without one
+fn load_terrain_config() -> (String, TerrainConfig) {
+    let mut args = std::env::args().skip_while(|arg| arg != "--terrain").skip(1);
+    let Some(preset) = args.next() else {
+        return ("default".to_string(), TerrainConfig::default());
+    };
+
+    let path = TerrainConfig::preset_path(&preset);
+    println!("Using terrain {path}");
+    let name = Path::new(&path).file_stem().map_or_else(|| "terrain".to_string(), |s| s.to_string_lossy().into_owned());
+    return (name, TerrainConfig::load(&path).unwrap_or_else(|e| panic!("Failed to load {path}: {e}")));
+}
 
 struct PendingUnload {
     _coords: (i32, i32, i32),
     counter: sgpu::Counter,
     face_loc: crate::renderer::BufferLocation,
-    cmd_slot: usize,
 }
 
 pub struct Application {
@@ -34,10 +73,22 @@ pub struct Application {
     renderer: Renderer,
     face_buffer: FaceBuffer,
     indirect_buffer: IndirectDrawBuffer,
+    staging: StagingBuffer,
+    // finished meshes that didn't fit in the staging buffer, uploaded first next frame
+    unstaged: VecDeque<WorkResult>,
     world: World,
     worker_pool: WorkerPool,
+    // only used for the stats, the workers have their own
+    generator: Arc<dyn Generator>,
+    registry: Arc<BlockRegistry>,
+    place_block: Block,
     size: PhysicalSize<u32>,
     pending_unloads: Vec<PendingUnload>,
+    // a compaction pass is walking down the face buffer, the next frame continues below compact_cursor
+    compacting: bool,
+    compact_cursor: u64,
+    compact_moved: bool,
+    compact_wait: u32,
 }
 
 impl Application {
@@ -55,17 +106,29 @@ impl Application {
             },
         );
 
-        let face_buffer = FaceBuffer::new(MAX_FACES);
-        let indirect_buffer = IndirectDrawBuffer::new(MAX_COMMANDS);
-        let renderer = Renderer::new(size);
+        let registry = Arc::new(BlockRegistry::load(BLOCKS_PATH).unwrap_or_else(|e| panic!("Failed to load {BLOCKS_PATH}: {e}")));
+        let place_block = registry.get(PLACE_BLOCK).unwrap_or_else(|| panic!("{BLOCKS_PATH} has no {PLACE_BLOCK}"));
+
+        let face_buffer = FaceBuffer::new(INITIAL_FACES, MAX_FACES);
+        let indirect_buffer = IndirectDrawBuffer::new(INITIAL_COMMANDS);
+        let staging = StagingBuffer::new(STAGING_BYTES);
+        let renderer = Renderer::new(size, &registry, INITIAL_COMMANDS);
         let camera = Camera::new(vec3(0.0, 32.0, 0.0), size.width as f32 / size.height as f32);
-        let mut world = World::new(GENERATION_RADIUS, UNLOAD_RADIUS);
+        let (terrain_name, terrain) = load_terrain_config();
+        let save_dir = format!("{SAVES_DIR}/{terrain_name}-{SEED}");
+        let store = Arc::new(ChunkStore::new(&save_dir).unwrap_or_else(|e| panic!("Failed to open {save_dir}: {e}")));
+        let mut world = World::new(GENERATION_RADIUS, UNLOAD_RADIUS, store, CHUNK_CACHE_BYTES);
         let cache = world.chunk_cache();
-        let mut worker_pool = WorkerPool::new(NUM_WORKERS, SEED, cache);
+        let focus = LoadFocus {
+            center: (0, 1, 0),
+            frustum: Some(Frustum::from_view_proj(&camera.view_proj())),
+        };
+        let generator = create_generator(SEED, &registry, terrain).unwrap_or_else(|e| panic!("Failed to create the terrain generator: {e}"));
+        let mut worker_pool = WorkerPool::new(NUM_WORKERS, generator.clone(), MESHING_MODE, focus, registry.clone(), world.store(), cache);
 
         let (to_load, _) = world.update(0, 1, 0);
-        for coords in to_load {
-            worker_pool.submit(WorkItem { coords });
+        for item in to_load {
+            worker_pool.submit(item);
         }
 
         Application {
@@ -75,10 +138,19 @@ impl Application {
             renderer,
             face_buffer,
             indirect_buffer,
+            staging,
+            unstaged: VecDeque::new(),
             world,
             worker_pool,
+            generator,
+            registry,
+            place_block,
             size,
             pending_unloads: Vec::new(),
+            compacting: false,
+            compact_cursor: u64::MAX,
+            compact_moved: false,
+            compact_wait: 0,
         }
     }
 
@@ -97,41 +169,78 @@ impl Application {
         self.size = size;
     }
 
-    // the data in indirect buffer needs to be zeroed or else there will be artifacts..
-    // hmm
-    // i need to leave it for now cuz i havent expericed and issue and idk a good way to impl this
+    // frees the face ranges nothing reads anymore and the buffers replaced by growing
     fn drain_pending(&mut self) {
         let mut i = 0;
         while i < self.pending_unloads.len() {
             if sgpu::poll(self.pending_unloads[i].counter) {
                 let p = self.pending_unloads.swap_remove(i);
                 self.face_buffer.free(p.face_loc);
-                self.indirect_buffer.free_slot(p.cmd_slot);
             } else {
                 i += 1;
             }
         }
+        self.staging.reclaim();
+        self.face_buffer.destroy_retired();
+        self.indirect_buffer.destroy_retired();
+        self.renderer.destroy_retired();
     }
 
     fn poll_worker_results(&mut self, uploads: &mut Vec<sgpu::Counter>) {
+        // meshes are written into the staging buffer right away and copied in one go once they all have a place
         let mut results = Vec::new();
-        while let Some(result) = self.worker_pool.try_recv() {
-            results.push(result);
+        let mut budget = UPLOAD_BYTES_PER_FRAME;
+        while budget > 0
+            && let Some(result) = self.unstaged.pop_front().or_else(|| self.worker_pool.try_recv())
+        {
+            // the chunk was unloaded or remeshed again while this job was running
+            if !self.world.is_current(result.coords, result.epoch) {
+                continue;
+            }
+            if result.mesh.faces.is_empty() {
+                results.push((result, None));
+                continue;
+            }
+            let Some(src_offset) = self.staging.write(&result.mesh.faces) else {
+                self.unstaged.push_front(result);
+                break;
+            };
+            budget = budget.saturating_sub(std::mem::size_of_val(result.mesh.faces.as_slice()));
+            results.push((result, Some(src_offset)));
         }
         if results.is_empty() {
             return;
         }
 
+        let mut shrunk = false;
+        let mut regions = Vec::new();
+
+        // a remeshed chunk keeps its draw command, only the old faces are freed.
+        // chunks whose mesh came out empty lose their command too
+        let mut replaced = Vec::new();
+        let mut emptied = Vec::new();
+
         let mut cmd = record(QueueType::Transfer);
-        for result in results {
-            if result.mesh.faces.is_empty() {
-                self.world.mark_loaded_empty(result.coords);
+        for (result, src_offset) in results {
+            let Some(src_offset) = src_offset else {
+                emptied.extend(self.world.mark_loaded_empty(result.coords));
                 continue;
-            }
-
-            let face_loc = self.face_buffer.allocate(result.mesh.faces.len());
-            let cmd_slot = self.indirect_buffer.allocate_slot();
+            };
 
+            let face_loc = match self.face_buffer.allocate(&mut cmd, result.mesh.faces.len()) {
+                Ok(face_loc) => face_loc,
+                Err(e) => {
+                    // draw less instead of crashing, the chunks dropped by the smaller radius make room for this one
+                    if !shrunk {
+                        let radius = self.world.shrink_radius();
+                        eprintln!("{e}, shrinking the generation radius to {radius}");
+                        shrunk = true;
+                    }
+                    self.start_compacting();
+                    self.world.retry_mesh(result.coords);
+                    continue;
+                }
+            };
             let face_offset = (face_loc.offset / std::mem::size_of::<Face>() as u64) as u32;
             let draw_cmd = IndirectDrawCommand {
                 vertex_count: result.mesh.faces.len() as u32 * 6,
@@ -142,30 +251,181 @@ impl Application {
                 _pad: 0.0,
             };
 
-            cmd.update_buffer(&self.face_buffer.raw(), face_loc.offset, &result.mesh.faces);
-            cmd.update_buffer(&self.indirect_buffer.raw(), self.indirect_buffer.slot_offset(cmd_slot), &[draw_cmd]);
+            regions.push(BufferCopy {
+                src_offset,
+                dst_offset: face_loc.offset,
+                size: face_loc.size,
+            });
+            let cmd_slot = match self.world.cmd_slot(result.coords) {
+                Some(cmd_slot) => {
+                    self.indirect_buffer.set(&mut cmd, cmd_slot, draw_cmd);
+                    cmd_slot
+                }
+                None => self.indirect_buffer.push(&mut cmd, result.coords, draw_cmd),
+            };
 
-            self.world.mark_loaded(result.coords, face_loc, cmd_slot);
+            replaced.extend(self.world.mark_loaded(result.coords, face_loc, cmd_slot));
         }
 
-        uploads.push(submit(&[cmd]));
+        // the face buffer may have grown above, every region goes into the current one
+        if !regions.is_empty() {
+            cmd.copy_buffer(&self.staging.raw(), &self.face_buffer.raw(), &regions);
+        }
+        self.remove_draw_commands(&mut cmd, &mut emptied);
+        let counter = submit(&[cmd]);
+        self.staging.submitted(counter);
+        uploads.push(counter);
+        for info in replaced.into_iter().chain(emptied) {
+            self.pending_unloads.push(PendingUnload {
+                _coords: info.coords,
+                counter,
+                face_loc: info.face_loc,
+            });
+        }
     }
 
-    fn submit_unload_zeroes(&mut self, unloads: Vec<ChunkUnloadInfo>) {
-        let zero_cmd = IndirectDrawCommand::zeroed();
+    // removes the draw commands of unloaded meshes, their faces are freed once cmd is done
+    fn submit_unloads(&mut self, mut unloads: Vec<ChunkUnloadInfo>) {
+        if unloads.is_empty() {
+            return;
+        }
+        let mut cmd = record(QueueType::Transfer);
+        self.remove_draw_commands(&mut cmd, &mut unloads);
+        let counter = submit(&[cmd]);
         for info in unloads {
-            let mut transfer_cmd = record(QueueType::Transfer);
-            transfer_cmd.update_buffer(&self.indirect_buffer.raw(), self.indirect_buffer.slot_offset(info.cmd_slot), &[zero_cmd]);
-            let counter = submit(&[transfer_cmd]);
             self.pending_unloads.push(PendingUnload {
                 _coords: info.coords,
                 counter,
                 face_loc: info.face_loc,
-                cmd_slot: info.cmd_slot,
             });
         }
     }
 
+    // highest slot first, so the command moved into a freed slot is never one that still has to go
+    fn remove_draw_commands(&mut self, cmd: &mut sgpu::CommandBuffer, unloads: &mut [ChunkUnloadInfo]) {
+        unloads.sort_unstable_by(|a, b| b.cmd_slot.cmp(&a.cmd_slot));
+        for info in unloads.iter() {
+            if let Some((coords, cmd_slot)) = self.indirect_buffer.swap_remove(cmd, info.cmd_slot) {
+                self.world.remap_slot(coords, cmd_slot);
+            }
+        }
+    }
+
+    // gives back what shrink_radius took once the face buffer has room for another shell of chunks.
+    // asks for twice what the shell should take so the radius doesn't flip back and forth
+    fn restore_radius(&mut self) {
+        if self.compacting {
+            return;
+        }
+        let Some(new_chunks) = self.world.radius_growth() else {
+            return;
+        };
+        let faces = self.face_buffer.stats();
+        let average_mesh = faces.used_bytes / faces.allocations.max(1) as u64;
+        let room = faces.largest_free_block + (self.face_buffer.max_capacity() - faces.capacity);
+        if room >= 2 * average_mesh * new_chunks as u64 {
+            let radius = self.world.grow_radius();
+            println!("Face buffer has room again, growing the generation radius to {radius}");
+        }
+    }
+
+    // starts a pass from the end of the face buffer, unless one is already on its way down
+    fn start_compacting(&mut self) {
+        if self.compacting {
+            return;
+        }
+        self.compacting = true;
+        self.compact_cursor = u64::MAX;
+        self.compact_moved = false;
+        self.compact_wait = 0;
+    }
+
+    // moves meshes from the end of the face buffer into the lowest holes that fit them, walking down
+    // a bit further every frame, so big meshes fit again. the old ranges are freed like unloaded meshes
+    // once nothing reads them
+    fn compact_faces(&mut self, uploads: &mut Vec<sgpu::Counter>) {
+        if !self.compacting {
+            if self.compact_wait > 0 {
+                self.compact_wait -= 1;
+                return;
+            }
+            if self.face_buffer.stats().fragmentation <= COMPACT_FRAGMENTATION {
+                return;
+            }
+            self.start_compacting();
+        }
+
+        let mut regions = Vec::new();
+        let mut moved = Vec::new();
+        let mut budget = COMPACT_BYTES_PER_FRAME;
+        let mut visits = COMPACT_VISITS_PER_FRAME;
+        let mut first_free = self.face_buffer.first_free_offset();
+        let mut finished = true;
+        while let Some(old) = self.face_buffer.prev_allocation(self.compact_cursor) {
+            // everything below the lowest hole is already packed
+            if first_free.is_none_or(|free| old.offset < free) {
+                break;
+            }
+            // a mesh bigger than the whole budget still gets moved on its own
+            if visits == 0 || (old.size > budget && !moved.is_empty()) {
+                finished = false;
+                break;
+            }
+            visits -= 1;
+            self.compact_cursor = old.offset;
+
+            // meshes waiting on an unload or on a move aren't in the world anymore, they get freed in a frame or two
+            let Some((coords, cmd_slot)) = self.world.mesh_at(old.offset) else {
+                continue;
+            };
+            // no hole further down is big enough for this one, smaller ones might still fit
+            let Some(new) = self.face_buffer.allocate_below(old) else {
+                continue;
+            };
+            if first_free == Some(new.offset) {
+                first_free = self.face_buffer.first_free_offset();
+            }
+
+            budget = budget.saturating_sub(old.size);
+            regions.push(BufferCopy {
+                src_offset: old.offset,
+                dst_offset: new.offset,
+                size: old.size,
+            });
+            self.world.relocate_mesh(coords, new);
+            moved.push((coords, old, new, cmd_slot));
+        }
+
+        self.compact_moved |= !moved.is_empty();
+        if finished {
+            self.compacting = false;
+            // as compact as it gets until more meshes are unloaded, don't walk the whole buffer again every frame
+            if !self.compact_moved {
+                self.compact_wait = COMPACT_RETRY_FRAMES;
+            }
+        }
+        if moved.is_empty() {
+            return;
+        }
+
+        let mut cmd = record(QueueType::Transfer);
+        // meshes uploaded this frame might be among the ones being moved
+        for counter in uploads.iter() {
+            cmd.wait_for(*counter, PipelineStage::ALL_COMMANDS);
+        }
+        cmd.copy_buffer(&self.face_buffer.raw(), &self.face_buffer.raw(), &regions);
+        for &(_, _, new, cmd_slot) in &moved {
+            let first_vertex = (new.offset / std::mem::size_of::<Face>() as u64) as u32 * 6;
+            self.indirect_buffer.set_first_vertex(&mut cmd, cmd_slot, first_vertex);
+        }
+        let counter = submit(&[cmd]);
+        uploads.push(counter);
+
+        for (coords, old, _, _) in moved {
+            self.pending_unloads.push(PendingUnload { _coords: coords, counter, face_loc: old });
+        }
+    }
+
     fn wait_for_pending(&self, cmd: &mut sgpu::CommandBuffer, uploads: &[sgpu::Counter]) {
         for counter in uploads {
             cmd.wait_for(*counter, PipelineStage::ALL_COMMANDS);
@@ -175,14 +435,84 @@ impl Application {
         }
     }
 
+    // left click breaks the block under the crosshair, right click places one against it
+    fn edit_blocks(&mut self) {
+        let breaking = self.input_manager.mouse_just_pressed(MouseButton::Left);
+        let placing = self.input_manager.mouse_just_pressed(MouseButton::Right);
+        if !breaking && !placing {
+            return;
+        }
+
+        let Some(hit) = self.world.raycast(self.camera.position, self.camera.forward(), REACH, &self.registry) else {
+            return;
+        };
+
+        if breaking {
+            self.world.set_block(hit.block, Block::AIR);
+        } else if hit.normal != (0, 0, 0) {
+            let target = (hit.block.0 + hit.normal.0, hit.block.1 + hit.normal.1, hit.block.2 + hit.normal.2);
+            let eye = self.camera.position.floor().as_ivec3();
+            if target != (eye.x, eye.y, eye.z) {
+                self.world.set_block(target, self.place_block);
+            }
+        }
+    }
+
+    fn print_stats(&mut self) {
+        let pool = self.worker_pool.stats();
+        let cache = self.world.chunk_cache().stats();
+        let faces = self.face_buffer.stats();
+        let (x, z) = (self.camera.position.x.floor() as i32, self.camera.position.z.floor() as i32);
+        println!(
+            "position: ({:.1}, {:.1}, {:.1}), terrain height: {}, biome: {:?}",
+            self.camera.position.x,
+            self.camera.position.y,
+            self.camera.position.z,
+            self.generator.sample_height(x, z),
+            self.generator.biome_at(x, z)
+        );
+        println!(
+            "workers: {}, queued: {}, waiting: {}, {:.1} jobs/s, gen {:.2?}, mesh {:.2?}, cache: {} chunks ({} pinned), {:.1} MiB",
+            pool.workers,
+            pool.queued,
+            pool.waiting,
+            pool.jobs_per_sec,
+            pool.avg_generate_time,
+            pool.avg_mesh_time,
+            cache.chunks,
+            cache.pinned,
+            cache.bytes as f64 / (1024.0 * 1024.0)
+        );
+        println!(
+            "face buffer: {:.1} / {:.1} MiB (up to {:.1} MiB) in {} meshes, largest free block {:.1} MiB, {:.0}% fragmented",
+            faces.used_bytes as f64 / (1024.0 * 1024.0),
+            faces.capacity as f64 / (1024.0 * 1024.0),
+            self.face_buffer.max_capacity() as f64 / (1024.0 * 1024.0),
+            faces.allocations,
+            faces.largest_free_block as f64 / (1024.0 * 1024.0),
+            faces.fragmentation * 100.0
+        );
+        println!(
+            "staging: {:.1} / {:.1} MiB in flight, {} meshes waiting for space",
+            self.staging.used() as f64 / (1024.0 * 1024.0),
+            self.staging.capacity() as f64 / (1024.0 * 1024.0),
+            self.unstaged.len()
+        );
+    }
+
     pub fn update(&mut self, dt: f64) {
         self.camera.process_input(&self.input_manager, dt);
+        self.edit_blocks();
+        if self.input_manager.just_pressed(STATS_KEY) {
+            self.print_stats();
+        }
         self.input_manager.poll();
 
         let mut uploads = Vec::new();
 
         self.drain_pending();
         self.poll_worker_results(&mut uploads);
+        self.compact_faces(&mut uploads);
 
         let mut acquired = self.swapchain.acquire_image();
         let vp = self.camera.view_proj();
@@ -194,12 +524,16 @@ impl Application {
 
         cmd.global_barrier(&GlobalBarrier {
             previous_accesses: &[AccessType::TransferWrite],
-            next_accesses: &[AccessType::VertexShaderStorageRead, AccessType::IndirectBuffer],
+            next_accesses: &[AccessType::ComputeShaderStorageRead, AccessType::VertexShaderStorageRead],
         });
 
-        self.renderer.render(&mut cmd, acquired.image(), &self.face_buffer, &self.indirect_buffer, &vp, self.indirect_buffer.count() as u32);
+        self.renderer.reserve_commands(self.indirect_buffer.capacity());
+        self.renderer.render(&mut cmd, acquired.image(), &self.face_buffer, &self.indirect_buffer, &vp);
 
         let submit_counter = submit(&[cmd]);
+        self.face_buffer.retire_after(submit_counter);
+        self.indirect_buffer.retire_after(submit_counter);
+        self.renderer.retire_after(submit_counter);
         self.swapchain.present(&mut acquired, submit_counter);
     }
 
@@ -209,18 +543,33 @@ impl Application {
         let cy = (pos.y / 32.0) as i32;
         let cz = (pos.z / 32.0) as i32;
 
+        self.restore_radius();
         let (to_load, to_unload) = self.world.update(cx, cy, cz);
 
-        for coords in to_load {
-            self.worker_pool.submit(WorkItem { coords });
+        self.worker_pool.set_focus(LoadFocus {
+            center: (cx, cy, cz),
+            frustum: Some(Frustum::from_view_proj(&self.camera.view_proj())),
+        });
+
+        for coords in self.world.take_cancelled() {
+            self.worker_pool.cancel(coords);
+        }
+
+        for item in to_load {
+            self.worker_pool.submit(item);
+        }
+
+        for item in self.world.take_remesh() {
+            self.worker_pool.submit(item);
         }
 
-        self.submit_unload_zeroes(to_unload);
+        self.submit_unloads(to_unload);
     }
 }
 
 impl Drop for Application {
     fn drop(&mut self) {
+        self.world.flush();
         sgpu::wait_idle();
     }
 }
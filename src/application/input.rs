@@ -105,6 +105,18 @@ impl InputManager {
         self.released_keys.contains(&key)
     }
 
+    pub fn is_mouse_down(&self, button: MouseButton) -> bool {
+        self.mouse_buttons_held.contains(&button)
+    }
+
+    pub fn mouse_just_pressed(&self, button: MouseButton) -> bool {
+        self.mouse_buttons_pressed.contains(&button)
+    }
+
+    pub fn mouse_just_released(&self, button: MouseButton) -> bool {
+        self.mouse_buttons_released.contains(&button)
+    }
+
     pub fn mouse_delta(&self) -> (f64, f64) {
         self.mouse_delta
     }
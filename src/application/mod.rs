@@ -3,7 +3,7 @@ mod input;
 
 use std::time::{Duration, Instant};
 
-pub use app::Application;
+pub use app::{Application, BLOCKS_PATH};
 pub use input::InputManager;
 
 use winit::{
@@ -0,0 +1,157 @@
+use super::{Block, BlockRegistry};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Biome {
+    Desert,
+    Plains,
+    Forest,
+    Mountains,
+    Tundra,
+}
+
+// how quickly biomes blend into each other, in climate units. smaller is sharper
+const BLEND_WIDTH: f64 = 0.15;
+
+struct BiomeSpec {
+    biome: Biome,
+    // where the biome sits in climate space, both from -1 to 1
+    temperature: f64,
+    humidity: f64,
+    surface: &'static str,
+    filler: &'static str,
+    filler_depth: i32,
+    // the terrain height is base_height + height_scale * noise
+    base_height: f64,
+    height_scale: f64,
+    // chance of a tree growing on a surface block
+    vegetation: f64,
+    // chance of a boulder on a surface block
+    boulders: f64,
+}
+
+const BIOMES: [BiomeSpec; 5] = [
+    BiomeSpec {
+        biome: Biome::Desert,
+        temperature: 0.6,
+        humidity: -0.6,
+        surface: "sand",
+        filler: "sand",
+        filler_depth: 4,
+        base_height: 4.0,
+        height_scale: 8.0,
+        vegetation: 0.0,
+        boulders: 0.001,
+    },
+    BiomeSpec {
+        biome: Biome::Plains,
+        temperature: 0.2,
+        humidity: -0.1,
+        surface: "grass",
+        filler: "dirt",
+        filler_depth: 3,
+        base_height: 6.0,
+        height_scale: 12.0,
+        vegetation: 0.005,
+        boulders: 0.0005,
+    },
+    BiomeSpec {
+        biome: Biome::Forest,
+        temperature: 0.2,
+        humidity: 0.6,
+        surface: "grass",
+        filler: "dirt",
+        filler_depth: 4,
+        base_height: 10.0,
+        height_scale: 18.0,
+        vegetation: 0.04,
+        boulders: 0.0,
+    },
+    BiomeSpec {
+        biome: Biome::Mountains,
+        temperature: -0.3,
+        humidity: 0.0,
+        surface: "stone",
+        filler: "stone",
+        filler_depth: 1,
+        base_height: 30.0,
+        height_scale: 60.0,
+        vegetation: 0.0,
+        boulders: 0.004,
+    },
+    BiomeSpec {
+        biome: Biome::Tundra,
+        temperature: -0.7,
+        humidity: 0.3,
+        surface: "snow",
+        filler: "dirt",
+        filler_depth: 3,
+        base_height: 8.0,
+        height_scale: 14.0,
+        vegetation: 0.002,
+        boulders: 0.002,
+    },
+];
+
+pub struct BiomeDefinition {
+    pub biome: Biome,
+    temperature: f64,
+    humidity: f64,
+    pub surface: Block,
+    pub filler: Block,
+    pub filler_depth: i32,
+    pub base_height: f64,
+    pub height_scale: f64,
+    pub vegetation: f64,
+    pub boulders: f64,
+}
+
+impl BiomeDefinition {
+    fn climate_distance_sq(&self, temperature: f64, humidity: f64) -> f64 {
+        let dt = self.temperature - temperature;
+        let dh = self.humidity - humidity;
+        return dt * dt + dh * dh;
+    }
+}
+
+// every biome with its blocks looked up in the registry
+pub struct BiomeTable {
+    biomes: Vec<BiomeDefinition>,
+}
+
+impl BiomeTable {
+    pub fn new(registry: &BlockRegistry) -> BiomeTable {
+        let block = |name: &str| registry.get(name).unwrap_or_else(|| panic!("block registry has no {name}"));
+        let biomes = BIOMES
+            .iter()
+            .map(|spec| BiomeDefinition {
+                biome: spec.biome,
+                temperature: spec.temperature,
+                humidity: spec.humidity,
+                surface: block(spec.surface),
+                filler: block(spec.filler),
+                filler_depth: spec.filler_depth,
+                base_height: spec.base_height,
+                height_scale: spec.height_scale,
+                vegetation: spec.vegetation,
+                boulders: spec.boulders,
+            })
+            .collect();
+        return BiomeTable { biomes };
+    }
+
+    // the biome closest to the climate
+    pub fn nearest(&self, temperature: f64, humidity: f64) -> &BiomeDefinition {
+        return self.biomes.iter().min_by(|a, b| a.climate_distance_sq(temperature, humidity).total_cmp(&b.climate_distance_sq(temperature, humidity))).unwrap();
+    }
+
+    // how much every biome contributes to a column, the weights add up to 1.
+    // they change continuously with the climate so heights blend smoothly across borders
+    pub fn weights(&self, temperature: f64, humidity: f64) -> impl Iterator<Item = (&BiomeDefinition, f64)> {
+        let distances: Vec<f64> = self.biomes.iter().map(|b| b.climate_distance_sq(temperature, humidity)).collect();
+        // relative to the nearest biome so far away climates don't underflow to all zeros
+        let nearest = distances.iter().copied().fold(f64::INFINITY, f64::min);
+        let raw: Vec<f64> = distances.iter().map(|d| (-(d - nearest) / (BLEND_WIDTH * BLEND_WIDTH)).exp()).collect();
+        let total: f64 = raw.iter().sum();
+        return self.biomes.iter().zip(raw).map(move |(b, w)| (b, w / total));
+    }
+}
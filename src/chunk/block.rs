@@ -1,37 +1,66 @@
 #[derive(Clone, Copy, PartialEq)]
 pub struct Block {
-    id: u8,
+    id: u16,
 }
 
 impl Block {
+    #[inline]
+    pub const fn new(id: u16) -> Block {
+        return Block { id };
+    }
+
     #[inline]
     pub fn is_air(&self) -> bool {
         return self.id == 0;
     }
 
     #[inline]
-    pub fn get_id(&self) -> u8 {
+    pub fn get_id(&self) -> u16 {
         return self.id;
     }
 
+    // every other block is defined by the BlockRegistry
     pub const AIR: Block = Block { id: 0 };
-    pub const GRASS: Block = Block { id: 1 };
 }
 
 #[repr(C, packed)]
 #[derive(Clone, Copy, PartialEq)]
 pub struct Face {
     data: u32,
+    extra: u32,
 }
 
+// data:
 // x, y and z range from 0 to 31.
 // normal is 3 bits.
-// block_id is 16 bits
-// block | normal | z | y | x.
+// width and height are stored minus one, 5 bits each.
+// flip is 1 bit, set when the quad should be split along the 1-3 diagonal.
+// flip | height | width | normal | z | y | x.
+//
+// extra:
+// block_id is 16 bits.
+// ao is 2 bits per corner, 8 bits.
+// ao | block.
+//
+// width runs along the first axis that is not the normal axis (in x, y, z order), height along the second.
 impl Face {
-    pub fn new(x: u32, y: u32, z: u32, normal: u32, block: Block) -> Face {
+    pub fn new(x: u32, y: u32, z: u32, normal: u32, ao: [u32; 4], block: Block) -> Face {
+        return Face::new_quad(x, y, z, normal, 1, 1, ao, block);
+    }
+
+    pub fn new_quad(x: u32, y: u32, z: u32, normal: u32, width: u32, height: u32, ao: [u32; 4], block: Block) -> Face {
+        let packed_ao = ao[0] | (ao[1] << 2) | (ao[2] << 4) | (ao[3] << 6);
+        // split the quad along the brighter diagonal to avoid anisotropy artifacts
+        let flip = (ao[0] + ao[2] < ao[1] + ao[3]) as u32;
+
         return Face {
-            data: (x | (y << 5) | (z << 10) | (normal << 15) | ((block.get_id() as u32) << 18)),
+            data: (x | (y << 5) | (z << 10) | (normal << 15) | ((width - 1) << 18) | ((height - 1) << 23) | (flip << 28)),
+            extra: ((block.get_id() as u32) | (packed_ao << 16)),
         };
     }
+
+    // the packed data and extra words, as they are uploaded
+    pub fn bits(&self) -> [u32; 2] {
+        return [self.data, self.extra];
+    }
 }
@@ -0,0 +1,185 @@
+use noise::*;
+
+// large open caverns where this noise is high
+const CHEESE_FREQUENCY: f64 = 0.012;
+const CHEESE_THRESHOLD: f64 = 0.4;
+// cheese caves stay this far below the surface so they don't eat the terrain away
+const CHEESE_MIN_DEPTH: i32 = 12;
+
+// long tunnels along the zero crossings of two noises
+const WORM_FREQUENCY: f64 = 0.012;
+const WORM_RADIUS: f64 = 0.07;
+// worms can break through the surface, making cave entrances, but not carve above it
+const WORM_MIN_DEPTH: i32 = 0;
+
+// both carvers only read world positions, so caves line up across chunk borders
+pub struct Caves {
+    cheese: Fbm<Simplex>,
+    worm_a: Simplex,
+    worm_b: Simplex,
+    cheese_enabled: bool,
+    worm_enabled: bool,
+}
+
+impl Caves {
+    pub fn new(seed: u32, cheese_enabled: bool, worm_enabled: bool) -> Caves {
+        return Caves {
+            cheese: Fbm::<Simplex>::new(seed).set_octaves(2).set_frequency(CHEESE_FREQUENCY),
+            worm_a: Simplex::new(seed.wrapping_add(1)),
+            worm_b: Simplex::new(seed.wrapping_add(2)),
+            cheese_enabled,
+            worm_enabled,
+        };
+    }
+
+    // true if the solid block at x, y, z should be hollowed out. surface is the terrain height of the column
+    pub fn carves(&self, x: i32, y: i32, z: i32, surface: i32) -> bool {
+        let depth = surface - y;
+        let point = [x as f64, y as f64, z as f64];
+
+        if self.cheese_enabled && depth >= CHEESE_MIN_DEPTH {
+            // squashed vertically so caverns are wider than they are tall
+            if self.cheese.get([point[0], point[1] * 2.0, point[2]]) > CHEESE_THRESHOLD {
+                return true;
+            }
+        }
+
+        if self.worm_enabled && depth >= WORM_MIN_DEPTH {
+            let scaled = point.map(|p| p * WORM_FREQUENCY);
+            if self.worm_a.get(scaled).abs() < WORM_RADIUS && self.worm_b.get(scaled).abs() < WORM_RADIUS {
+                return true;
+            }
+        }
+
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::*;
+    use crate::test_utils::registry;
+
+    fn generator(overhangs: bool, cheese_caves: bool, worm_caves: bool) -> NoiseGenerator {
+        let config = TerrainConfig {
+            stages: GeneratorStages { overhangs, cheese_caves, worm_caves },
+            ..TerrainConfig::default()
+        };
+        return NoiseGenerator::new(SEED, &registry(), config);
+    }
+
+    // the highest chunk below every column's surface, out of reach of trees and boulders
+    fn underground_origin(generator: &NoiseGenerator, x: i32, z: i32) -> (i32, i32, i32) {
+        let side = CHUNK_SIDE as i32;
+        let lowest = (0..side).flat_map(|cx| (0..side).map(move |cz| (cx, cz))).map(|(cx, cz)| generator.sample_height(x + cx, z + cz)).min().unwrap();
+        return (x, (lowest - FEATURE_DEPTH - 1 - side).div_euclid(side) * side, z);
+    }
+
+    // the air blocks below the terrain height of their column, sorted
+    fn carved(generator: &NoiseGenerator, (x, y, z): (i32, i32, i32)) -> Vec<(usize, usize, usize)> {
+        let blocks = generator.generate_blocks(x, y, z);
+        let mut carved = Vec::new();
+        for cz in 0..CHUNK_SIDE {
+            for cx in 0..CHUNK_SIDE {
+                let height = generator.sample_height(x + cx as i32, z + cz as i32);
+                for cy in 0..CHUNK_SIDE {
+                    if y + (cy as i32) < height && blocks[Chunk::get_index(cx, cy, cz)].is_air() {
+                        carved.push((cx, cy, cz));
+                    }
+                }
+            }
+        }
+        carved.sort_unstable();
+        return carved;
+    }
+
+    // a few chunks spread far apart so every carver shows up in some of them
+    fn sample_origins(generator: &NoiseGenerator) -> Vec<(i32, i32, i32)> {
+        let step = 5 * CHUNK_SIDE as i32;
+        return (-2..2).flat_map(|i| (-2..2).map(move |j| (i * step, j * step))).map(|(x, z)| underground_origin(generator, x, z)).collect();
+    }
+
+    #[test]
+    fn the_same_seed_carves_the_same_caves() {
+        let (a, b) = (generator(true, true, true), generator(true, true, true));
+        for (x, y, z) in sample_origins(&a) {
+            assert!(a.generate_blocks(x, y, z) == b.generate_blocks(x, y, z));
+        }
+
+        let caves = Caves::new(SEED, true, true);
+        let same = Caves::new(SEED, true, true);
+        let other = Caves::new(SEED + 1, true, true);
+        let points: Vec<(i32, i32, i32)> = (0..20000).map(|i| (i % 40 * 3, -i / 40 % 25 * 2, i / 1000 * 7)).collect();
+        assert!(points.iter().all(|&(x, y, z)| caves.carves(x, y, z, 50) == same.carves(x, y, z, 50)));
+        assert!(points.iter().any(|&(x, y, z)| caves.carves(x, y, z, 50) != other.carves(x, y, z, 50)));
+    }
+
+    #[test]
+    fn without_caves_nothing_below_the_surface_is_air() {
+        let solid = generator(false, false, false);
+        let origins = sample_origins(&solid);
+        for &origin in &origins {
+            assert!(carved(&solid, origin).is_empty(), "air below the surface in {origin:?}");
+        }
+
+        // overhangs alone only move the surface, they don't reach this deep
+        let overhangs = generator(true, false, false);
+        for &origin in &origins {
+            assert!(carved(&overhangs, origin).is_empty(), "air below the surface in {origin:?}");
+        }
+    }
+
+    #[test]
+    fn each_carver_only_adds_its_own_caves() {
+        let (cheese, worms, both) = (generator(false, true, false), generator(false, false, true), generator(false, true, true));
+        let (mut cheese_total, mut worm_total) = (0, 0);
+
+        for origin in sample_origins(&both) {
+            let cheese_air = carved(&cheese, origin);
+            let worm_air = carved(&worms, origin);
+            let mut union: Vec<(usize, usize, usize)> = cheese_air.iter().chain(&worm_air).copied().collect();
+            union.sort_unstable();
+            union.dedup();
+            assert_eq!(carved(&both, origin), union, "caves in {origin:?} aren't the two carvers combined");
+
+            // cheese caves keep away from the surface, worms don't
+            let (x, y, z) = origin;
+            for &(cx, cy, cz) in &cheese_air {
+                assert!(cheese.sample_height(x + cx as i32, z + cz as i32) - (y + cy as i32) >= super::CHEESE_MIN_DEPTH);
+            }
+            cheese_total += cheese_air.len();
+            worm_total += worm_air.len();
+        }
+        assert!(cheese_total > 0 && worm_total > 0, "{cheese_total} blocks of cheese caves and {worm_total} of worm tunnels");
+    }
+
+    #[test]
+    fn worm_tunnels_line_up_across_chunk_borders() {
+        let worms = generator(false, false, true);
+        let side = CHUNK_SIDE as i32;
+        let half = CHUNK_SIDE / 2;
+
+        // the first pair of chunks a tunnel runs through from one into the other
+        let (left, right) = (0..50)
+            .map(|i| underground_origin(&worms, i * side, 0))
+            .map(|(x, y, z)| ((x, y, z), (x + side, y, z)))
+            .find(|&(left, right)| {
+                let (a, b) = (worms.generate_blocks(left.0, left.1, left.2), worms.generate_blocks(right.0, right.1, right.2));
+                (0..CHUNK_SIDE * CHUNK_SIDE).any(|i| a[Chunk::get_index(CHUNK_SIDE - 1, i / CHUNK_SIDE, i % CHUNK_SIDE)].is_air() && b[Chunk::get_index(0, i / CHUNK_SIDE, i % CHUNK_SIDE)].is_air())
+            })
+            .expect("no tunnel crosses a chunk border");
+
+        // a chunk straddling the border has the same blocks as the two halves it covers
+        let a = worms.generate_blocks(left.0, left.1, left.2);
+        let b = worms.generate_blocks(right.0, right.1, right.2);
+        let middle = worms.generate_blocks(left.0 + half as i32, left.1, left.2);
+        for cz in 0..CHUNK_SIDE {
+            for cy in 0..CHUNK_SIDE {
+                for cx in 0..CHUNK_SIDE {
+                    let expected = if cx < half { a[Chunk::get_index(cx + half, cy, cz)] } else { b[Chunk::get_index(cx - half, cy, cz)] };
+                    assert!(middle[Chunk::get_index(cx, cy, cz)] == expected, "block ({cx}, {cy}, {cz}) of the chunk across {left:?} and {right:?} differs");
+                }
+            }
+        }
+    }
+}
@@ -7,8 +7,14 @@ pub struct ChunkMesh {
 pub const CHUNK_SIDE: usize = 32;
 pub const CHUNK_VOLUME: usize = 32 * 32 * 32;
 
+// blocks are stored as indices into a palette, bit packed into u64 words.
+// the index width grows in powers of two as the palette grows, and indices never straddle two words.
+// a chunk made of a single block type has no index data at all.
+#[derive(Clone)]
 pub struct Chunk {
-    pub blocks: [Block; CHUNK_VOLUME],
+    palette: Vec<Block>,
+    bits: u32,
+    data: Vec<u64>,
 }
 
 impl Chunk {
@@ -17,7 +23,184 @@ impl Chunk {
         return x + y * CHUNK_SIDE + z * CHUNK_SIDE * CHUNK_SIDE;
     }
 
-    pub fn new(blocks: [Block; CHUNK_VOLUME]) -> Self {
-        Chunk { blocks }
+    pub fn filled(block: Block) -> Self {
+        return Chunk {
+            palette: vec![block],
+            bits: 0,
+            data: Vec::new(),
+        };
+    }
+
+    pub fn from_blocks(blocks: &[Block; CHUNK_VOLUME]) -> Self {
+        let mut palette: Vec<Block> = Vec::new();
+        let mut indices = vec![0u16; CHUNK_VOLUME];
+
+        for (i, block) in blocks.iter().enumerate() {
+            indices[i] = match palette.iter().position(|b| b == block) {
+                Some(idx) => idx as u16,
+                None => {
+                    palette.push(*block);
+                    (palette.len() - 1) as u16
+                }
+            };
+        }
+
+        let bits = Chunk::bits_for(palette.len());
+        let mut chunk = Chunk {
+            palette,
+            bits,
+            data: vec![0; Chunk::words_for(bits)],
+        };
+
+        if bits > 0 {
+            for (i, idx) in indices.iter().enumerate() {
+                chunk.write_index(i, *idx as u64);
+            }
+        }
+
+        return chunk;
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> Block {
+        if self.bits == 0 {
+            return self.palette[0];
+        }
+
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        let idx = (self.data[index / per_word] >> shift) & ((1 << self.bits) - 1);
+        return self.palette[idx as usize];
+    }
+
+    pub fn set(&mut self, index: usize, block: Block) {
+        let idx = match self.palette.iter().position(|b| *b == block) {
+            Some(idx) => idx,
+            None => {
+                self.palette.push(block);
+                let bits = Chunk::bits_for(self.palette.len());
+                if bits != self.bits {
+                    self.repack(bits);
+                }
+                self.palette.len() - 1
+            }
+        };
+
+        if self.bits > 0 {
+            self.write_index(index, idx as u64);
+        }
+    }
+
+    pub fn unpack_into(&self, blocks: &mut [Block; CHUNK_VOLUME]) {
+        if self.bits == 0 {
+            blocks.fill(self.palette[0]);
+            return;
+        }
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = self.get(i);
+        }
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        return self.bits == 0;
+    }
+
+    pub fn palette(&self) -> &[Block] {
+        return &self.palette;
+    }
+
+    // bytes used by the chunk, including its heap allocations
+    pub fn memory_usage(&self) -> usize {
+        return std::mem::size_of::<Chunk>() + self.palette.capacity() * std::mem::size_of::<Block>() + self.data.capacity() * std::mem::size_of::<u64>();
+    }
+
+    // smallest power of two index width that can address the whole palette
+    fn bits_for(palette_len: usize) -> u32 {
+        if palette_len <= 1 {
+            return 0;
+        }
+
+        let needed = usize::BITS - (palette_len - 1).leading_zeros();
+        return needed.next_power_of_two();
+    }
+
+    fn words_for(bits: u32) -> usize {
+        if bits == 0 {
+            return 0;
+        }
+
+        let per_word = 64 / bits as usize;
+        return CHUNK_VOLUME.div_ceil(per_word);
+    }
+
+    #[inline]
+    fn write_index(&mut self, index: usize, idx: u64) {
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.data[index / per_word];
+        *word = (*word & !mask) | (idx << shift);
+    }
+
+    fn repack(&mut self, bits: u32) {
+        let old = Chunk {
+            palette: Vec::new(),
+            bits: self.bits,
+            data: std::mem::take(&mut self.data),
+        };
+
+        self.bits = bits;
+        self.data = vec![0; Chunk::words_for(bits)];
+
+        if old.bits == 0 {
+            // every block was palette entry 0, which is already what zeroed data means
+            return;
+        }
+
+        let old_per_word = 64 / old.bits as usize;
+        let old_mask = (1u64 << old.bits) - 1;
+        for i in 0..CHUNK_VOLUME {
+            let shift = (i % old_per_word) as u32 * old.bits;
+            let idx = (old.data[i / old_per_word] >> shift) & old_mask;
+            self.write_index(i, idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_grows_and_keeps_every_block() {
+        let mut chunk = Chunk::filled(Block::AIR);
+        let mut expected = vec![Block::AIR; CHUNK_VOLUME];
+        let mut seed = 12345u64;
+        for step in 0..100000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let index = (seed >> 33) as usize % CHUNK_VOLUME;
+            // few ids at first, then the full 16 bits so the index width has to grow to 16
+            let id = if step < 50000 { (seed >> 20) as u16 % 300 } else { (seed >> 20) as u16 };
+            chunk.set(index, Block::new(id));
+            expected[index] = Block::new(id);
+        }
+        assert_eq!(chunk.bits, 16);
+        assert!((0..CHUNK_VOLUME).all(|i| chunk.get(i) == expected[i]));
+
+        let rebuilt = Chunk::from_blocks(&expected.try_into().ok().unwrap());
+        assert!((0..CHUNK_VOLUME).all(|i| rebuilt.get(i) == chunk.get(i)));
+    }
+
+    #[test]
+    fn uniform_chunks_store_no_indices() {
+        let mut chunk = Chunk::filled(Block::new(7));
+        assert!(chunk.is_uniform());
+        assert_eq!(chunk.memory_usage(), std::mem::size_of::<Chunk>() + chunk.palette.capacity() * std::mem::size_of::<Block>());
+
+        chunk.set(5, Block::new(9));
+        assert_eq!(chunk.bits, 1);
+        assert!(chunk.get(5) == Block::new(9) && chunk.get(6) == Block::new(7));
+        assert!(Chunk::from_blocks(&[Block::new(3); CHUNK_VOLUME]).is_uniform());
     }
 }
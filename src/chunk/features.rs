@@ -0,0 +1,135 @@
+use super::{Block, CHUNK_SIDE, CHUNK_VOLUME, Chunk, hash_position};
+
+// how far a feature can reach sideways from its anchor column
+pub const FEATURE_REACH: i32 = 2;
+// how far a feature can reach above and below its base
+pub const FEATURE_HEIGHT: i32 = 8;
+pub const FEATURE_DEPTH: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FeatureKind {
+    Tree,
+    Boulder,
+}
+
+pub struct FeatureBlocks {
+    pub log: Block,
+    pub leaves: Block,
+    pub stone: Block,
+}
+
+// a structure anchored on the first air block above the ground.
+// chunks stamp every feature that reaches into them, including ones anchored in a neighbour,
+// so a chunk comes out the same no matter which of its neighbours was generated first
+pub struct Feature {
+    pub kind: FeatureKind,
+    pub base: (i32, i32, i32),
+    // picks the size and shape
+    pub variant: u32,
+}
+
+impl Feature {
+    // writes the part of the feature inside the chunk at origin, only into air.
+    // logs also replace leaves so overlapping trees keep their trunks
+    pub fn stamp(&self, blocks: &mut [Block; CHUNK_VOLUME], origin: (i32, i32, i32), palette: &FeatureBlocks) {
+        let mut place = |x: i32, y: i32, z: i32, block: Block| {
+            let (lx, ly, lz) = (x - origin.0, y - origin.1, z - origin.2);
+            let side = CHUNK_SIDE as i32;
+            if lx < 0 || ly < 0 || lz < 0 || lx >= side || ly >= side || lz >= side {
+                return;
+            }
+            let index = Chunk::get_index(lx as usize, ly as usize, lz as usize);
+            let current = blocks[index];
+            if current.is_air() || (block == palette.log && current == palette.leaves) {
+                blocks[index] = block;
+            }
+        };
+
+        let (bx, by, bz) = self.base;
+        match self.kind {
+            FeatureKind::Tree => {
+                let height = 4 + (self.variant % 3) as i32;
+                for dy in height - 2..=height + 1 {
+                    let radius: i32 = if dy < height { 2 } else { 1 };
+                    for dz in -radius..=radius {
+                        for dx in -radius..=radius {
+                            // round off some of the corners
+                            let corner = dx.abs() == radius && dz.abs() == radius;
+                            if corner && (dy > height || hash_position(self.variant, bx + dx, by + dy, bz + dz) % 2 == 0) {
+                                continue;
+                            }
+                            place(bx + dx, by + dy, bz + dz, palette.leaves);
+                        }
+                    }
+                }
+                for dy in 0..height {
+                    place(bx, by + dy, bz, palette.log);
+                }
+            }
+            FeatureKind::Boulder => {
+                // half buried in the ground
+                let radius = 1 + (self.variant % 2) as i32;
+                for dz in -radius..=radius {
+                    for dy in -radius..=radius {
+                        for dx in -radius..=radius {
+                            if dx * dx + dy * dy + dz * dz <= radius * radius + radius - 1 {
+                                place(bx + dx, by + dy - 1, bz + dz, palette.stone);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Generator, NoiseGenerator, SEED, TerrainConfig};
+    use crate::test_utils::registry;
+
+    // the first surface chunk along +x with a trunk in its last column whose leaves hang into the next chunk
+    fn chunk_with_a_tree_on_its_border(generator: &NoiseGenerator, log: Block, leaves: Block) -> (i32, i32, i32) {
+        let side = CHUNK_SIDE as i32;
+        for cx in 0..400 {
+            let (x, z) = (cx * side, 0);
+            let y = (generator.sample_height(x + side - 1, z + side / 2) + 2).div_euclid(side) * side;
+            let blocks = generator.generate_blocks(x, y, z);
+            let next = generator.generate_blocks(x + side, y, z);
+            for lz in 2..CHUNK_SIDE - 2 {
+                for ly in 0..CHUNK_SIDE {
+                    if blocks[Chunk::get_index(CHUNK_SIDE - 1, ly, lz)] == log && (0..CHUNK_SIDE).any(|ly| next[Chunk::get_index(0, ly, lz)] == leaves) {
+                        return (x, y, z);
+                    }
+                }
+            }
+        }
+        panic!("no tree on a chunk border");
+    }
+
+    #[test]
+    fn trees_across_a_border_are_the_same_in_any_generation_order() {
+        let registry = registry();
+        let (log, leaves) = (registry.get("log").unwrap(), registry.get("leaves").unwrap());
+        let side = CHUNK_SIDE as i32;
+        let generator = NoiseGenerator::new(SEED, &registry, TerrainConfig::default());
+        let (x, y, z) = chunk_with_a_tree_on_its_border(&generator, log, leaves);
+        // the tree's chunk, the one its leaves hang into and the two next to them
+        let origins = [(x, y, z), (x + side, y, z), (x, y, z - side), (x + side, y, z - side)];
+
+        let in_order: Vec<[Block; CHUNK_VOLUME]> = origins.iter().map(|&(x, y, z)| generator.generate_blocks(x, y, z)).collect();
+
+        // backwards on a fresh generator, each chunk on its own thread like the worker pool does
+        let generator = NoiseGenerator::new(SEED, &registry, TerrainConfig::default());
+        let generator = &generator;
+        let backwards: Vec<[Block; CHUNK_VOLUME]> = std::thread::scope(|s| {
+            let handles: Vec<_> = origins.iter().rev().map(|&(x, y, z)| s.spawn(move || generator.generate_blocks(x, y, z))).collect();
+            return handles.into_iter().rev().map(|h| h.join().unwrap()).collect();
+        });
+
+        for i in 0..origins.len() {
+            assert!(backwards[i] == in_order[i], "chunk at {:?} changed with the generation order", origins[i]);
+        }
+    }
+}
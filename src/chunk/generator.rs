@@ -1,39 +1,368 @@
 use super::*;
 use noise::*;
+use std::sync::Arc;
 
-pub struct Generator {
-    simplex: Simplex,
+// climate changes over thousands of blocks
+const CLIMATE_FREQUENCY: f64 = 0.0015;
+
+// 3d noise added to the height bias, this is what makes cliffs and overhangs
+const OVERHANG_FREQUENCY: f64 = 0.02;
+const OVERHANG_STRENGTH: f64 = 10.0;
+
+// columns whose surface is at most this far above the sea are sand
+const BEACH_HEIGHT: i32 = 2;
+// the top of the bedrock is jagged by up to this many blocks
+const BEDROCK_ROUGHNESS: u32 = 3;
+// mixed into the seed so features don't line up with other per position randomness
+const FEATURE_SALT: u32 = 0x5eed_f00d;
+
+struct Ore {
+    block: Block,
+    noise: Simplex,
+    frequency: f64,
+    threshold: f64,
+    min_y: i32,
+    max_y: i32,
+}
+
+// the seed of the world, also the one the golden file is recorded with
+pub const SEED: u32 = 69;
+
+// turns world positions into blocks. has to be deterministic, chunks generated
+// at different times and on different workers have to line up
+pub trait Generator: Send + Sync {
+    // the terrain height of the column, before overhangs and caves
+    fn sample_height(&self, x: i32, z: i32) -> i32;
+
+    // the biome whose surface and features the column gets
+    fn biome_at(&self, x: i32, z: i32) -> Biome;
+
+    fn generate_blocks(&self, x: i32, y: i32, z: i32) -> [Block; CHUNK_VOLUME];
+}
+
+// the generator picked by the terrain config
+pub fn create_generator(seed: u32, registry: &BlockRegistry, config: TerrainConfig) -> Result<Arc<dyn Generator>, HeightmapError> {
+    let Some(heightmap) = config.heightmap.clone() else {
+        return Ok(Arc::new(NoiseGenerator::new(seed, registry, config)));
+    };
+    return Ok(Arc::new(HeightmapGenerator::new(heightmap, NoiseGenerator::new(seed, registry, config))?));
+}
+
+// terrain from fractal noise, also does the caves, layers and features for HeightmapGenerator
+pub struct NoiseGenerator {
+    seed: u32,
+    terrain: Box<dyn NoiseFn<f64, 2> + Send + Sync>,
+    warp_x: Simplex,
+    warp_z: Simplex,
+    overhangs: Fbm<Simplex>,
+    temperature: Simplex,
+    humidity: Simplex,
+    caves: Caves,
+    biomes: BiomeTable,
+    ores: Vec<Ore>,
+    config: TerrainConfig,
+    stone: Block,
+    sand: Block,
+    water: Block,
+    bedrock: Block,
+    features: FeatureBlocks,
+}
+
+// cheap deterministic per position randomness
+pub fn hash_position(seed: u32, x: i32, y: i32, z: i32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x27d4eb2d) ^ (y as u32).wrapping_mul(0x165667b1) ^ (z as u32).wrapping_mul(0x9e3779b9);
+    h = (h ^ (h >> 15)).wrapping_mul(0x2c1b3c6d);
+    h = (h ^ (h >> 12)).wrapping_mul(0x297a2d39);
+    return h ^ (h >> 15);
 }
 
-impl Generator {
-    pub fn new(seed: u32) -> Generator {
-        return Generator { simplex: Simplex::new(seed) };
+impl NoiseGenerator {
+    pub fn new(seed: u32, registry: &BlockRegistry, config: TerrainConfig) -> NoiseGenerator {
+        let block = |name: &str| registry.get(name).unwrap_or_else(|| panic!("block registry has no {name}"));
+
+        let ores = config
+            .ores
+            .iter()
+            .enumerate()
+            .map(|(i, vein)| Ore {
+                block: block(&vein.block),
+                noise: Simplex::new(seed.wrapping_add(100 + i as u32)),
+                frequency: vein.frequency,
+                threshold: vein.threshold,
+                min_y: vein.min_y,
+                max_y: vein.max_y,
+            })
+            .collect();
+
+        let HeightNoise {
+            mode,
+            octaves,
+            frequency,
+            lacunarity,
+            persistence,
+        } = config.noise;
+        let terrain: Box<dyn NoiseFn<f64, 2> + Send + Sync> = match mode {
+            NoiseMode::Fbm => Box::new(Fbm::<Simplex>::new(seed).set_octaves(octaves).set_frequency(frequency).set_lacunarity(lacunarity).set_persistence(persistence)),
+            NoiseMode::Ridged => Box::new(RidgedMulti::<Simplex>::new(seed).set_octaves(octaves).set_frequency(frequency).set_lacunarity(lacunarity).set_persistence(persistence)),
+            NoiseMode::Billow => Box::new(Billow::<Simplex>::new(seed).set_octaves(octaves).set_frequency(frequency).set_lacunarity(lacunarity).set_persistence(persistence)),
+        };
+
+        return NoiseGenerator {
+            seed,
+            terrain,
+            warp_x: Simplex::new(seed.wrapping_add(5)),
+            warp_z: Simplex::new(seed.wrapping_add(6)),
+            overhangs: Fbm::<Simplex>::new(seed.wrapping_add(3)).set_octaves(3).set_frequency(OVERHANG_FREQUENCY),
+            temperature: Simplex::new(seed.wrapping_add(1)),
+            humidity: Simplex::new(seed.wrapping_add(2)),
+            caves: Caves::new(seed.wrapping_add(4), config.stages.cheese_caves, config.stages.worm_caves),
+            biomes: BiomeTable::new(registry),
+            ores,
+            stone: block("stone"),
+            sand: block("sand"),
+            water: block("water"),
+            bedrock: block("bedrock"),
+            features: FeatureBlocks {
+                log: block("log"),
+                leaves: block("leaves"),
+                stone: block("stone"),
+            },
+            config,
+        };
+    }
+
+    fn climate(&self, x: i32, z: i32) -> (f64, f64) {
+        let point = [x as f64 * CLIMATE_FREQUENCY, z as f64 * CLIMATE_FREQUENCY];
+        return (self.temperature.get(point), self.humidity.get(point));
+    }
+
+    // every biome's height curve, weighted by how close the column's climate is to it
+    pub fn noise_height(&self, x: i32, z: i32) -> i32 {
+        let (temperature, humidity) = self.climate(x, z);
+        let (mut px, mut pz) = (x as f64, z as f64);
+        let warp = self.config.domain_warp;
+        if warp.strength != 0.0 {
+            let point = [px * warp.frequency, pz * warp.frequency];
+            px += warp.strength * self.warp_x.get(point);
+            pz += warp.strength * self.warp_z.get(point);
+        }
+        let noise = self.terrain.get([px, pz]);
+
+        let scale = self.config.height_scale;
+        let weights = self.biomes.weights(temperature, humidity);
+        let height: f64 = match self.config.base_height {
+            Some(base) => base + weights.map(|(biome, weight)| weight * scale * biome.height_scale * noise).sum::<f64>(),
+            None => weights.map(|(biome, weight)| weight * (biome.base_height + scale * biome.height_scale * noise)).sum(),
+        };
+        return height as i32;
+    }
+
+    // positive density is solid. the height bias keeps the terrain around the heightmap,
+    // the 3d noise moves the surface sideways so it can hang over itself
+    fn is_solid(&self, x: i32, y: i32, z: i32, height: i32) -> bool {
+        let bias = (height - y) as f64;
+        if !self.config.stages.overhangs || bias.abs() > OVERHANG_STRENGTH {
+            return bias > 0.0;
+        }
+        return bias + OVERHANG_STRENGTH * self.overhangs.get([x as f64, y as f64, z as f64]) > 0.0;
+    }
+
+    fn bedrock_top(&self, x: i32, z: i32) -> i32 {
+        return self.config.bedrock_level + (hash_position(self.seed, x, 0, z) % BEDROCK_ROUGHNESS) as i32;
+    }
+
+    // the first ore whose vein covers the position, later veins never overwrite earlier ones
+    fn ore_at(&self, x: i32, y: i32, z: i32) -> Option<Block> {
+        return self
+            .ores
+            .iter()
+            .find(|ore| y >= ore.min_y && y <= ore.max_y && ore.noise.get([x as f64 * ore.frequency, y as f64 * ore.frequency, z as f64 * ore.frequency]) > ore.threshold)
+            .map(|ore| ore.block);
     }
 
-    pub fn sample_height(&self, x: i32, z: i32) -> i32 {
-        let height = self.simplex.get([x as f64 * 0.01, z as f64 * 0.01]);
+    // the feature anchored on the column, if any. only depends on the seed and the column
+    fn feature_at(&self, x: i32, z: i32, min_y: i32, max_y: i32, heights: &dyn Fn(i32, i32) -> i32) -> Option<Feature> {
+        let roll = hash_position(self.seed ^ FEATURE_SALT, x, 0, z);
+        let chance = roll as f64 / u32::MAX as f64;
+
+        let (temperature, humidity) = self.climate(x, z);
+        let biome = self.biomes.nearest(temperature, humidity);
+        let kind = if chance < biome.vegetation {
+            FeatureKind::Tree
+        } else if chance < biome.vegetation + biome.boulders {
+            FeatureKind::Boulder
+        } else {
+            return None;
+        };
+
+        // skip the expensive checks for features that can't reach the chunk
+        let height = heights(x, z);
+        let spread = if self.config.stages.overhangs { OVERHANG_STRENGTH as i32 } else { 0 };
+        if height + spread + FEATURE_HEIGHT < min_y || height - spread - FEATURE_DEPTH > max_y {
+            return None;
+        }
+
+        // the highest ground, overhangs can move it up to spread blocks away from the heightmap
+        let base = (height - spread..=height + spread).rev().find(|&y| self.is_solid(x, y - 1, z, height) && !self.is_solid(x, y, z, height))?;
+
+        // has to stand on dry, uncarved ground
+        if base <= self.config.sea_level || self.caves.carves(x, base - 1, z, height) {
+            return None;
+        }
+
+        return Some(Feature { kind, base: (x, base, z), variant: roll >> 8 });
+    }
 
-        return (height * 30.0) as i32;
+    // stamps every feature that reaches into the chunk, in a fixed order
+    fn place_features(&self, blocks: &mut [Block; CHUNK_VOLUME], x: i32, y: i32, z: i32, heights: &dyn Fn(i32, i32) -> i32) {
+        let side = CHUNK_SIDE as i32;
+        for az in z - FEATURE_REACH..z + side + FEATURE_REACH {
+            for ax in x - FEATURE_REACH..x + side + FEATURE_REACH {
+                if let Some(feature) = self.feature_at(ax, az, y, y + side - 1, heights) {
+                    feature.stamp(blocks, (x, y, z), &self.features);
+                }
+            }
+        }
     }
 
-    pub fn generate_blocks(&self, x: i32, y: i32, z: i32) -> [Block; CHUNK_VOLUME] {
+    // everything but the heightmap, heights gives the terrain height of any column
+    pub fn generate_with_heights(&self, x: i32, y: i32, z: i32, heights: &dyn Fn(i32, i32) -> i32) -> [Block; CHUNK_VOLUME] {
         let mut blocks = [Block::AIR; CHUNK_VOLUME];
 
         for cx in 0..CHUNK_SIDE {
             for cz in 0..CHUNK_SIDE {
                 let wx = x + cx as i32;
                 let wz = z + cz as i32;
-                let height = self.sample_height(wx, wz);
+                let height = heights(wx, wz);
+                let (temperature, humidity) = self.climate(wx, wz);
+                let biome = self.biomes.nearest(temperature, humidity);
+                let bedrock_top = self.bedrock_top(wx, wz);
+
+                // beaches and the sea floor are sand whatever the biome
+                let (surface, filler) = if height <= self.config.sea_level + BEACH_HEIGHT { (self.sand, self.sand) } else { (biome.surface, biome.filler) };
+
+                // how many solid blocks are directly above, only counted as far as the filler goes.
+                // starts above the chunk so the top blocks know what covers them
+                let top = y + CHUNK_SIDE as i32 - 1;
+                let mut covered = 0;
+                for wy in (y..=top + biome.filler_depth + 1).rev() {
+                    let solid = wy <= bedrock_top || self.is_solid(wx, wy, wz, height);
+                    let inside = wy <= top;
+
+                    if !solid {
+                        covered = 0;
+                        if inside && wy < self.config.sea_level {
+                            blocks[Chunk::get_index(cx, (wy - y) as usize, cz)] = self.water;
+                        }
+                        continue;
+                    }
 
-                for cy in 0..CHUNK_SIDE {
-                    let wy = y + cy as i32;
-                    if wy < height {
-                        blocks[Chunk::get_index(cx, cy, cz)] = Block::GRASS;
+                    if inside {
+                        let block = if wy <= bedrock_top {
+                            self.bedrock
+                        } else if self.caves.carves(wx, wy, wz, height) {
+                            Block::AIR
+                        } else if covered == 0 {
+                            surface
+                        } else if covered <= biome.filler_depth {
+                            filler
+                        } else {
+                            self.ore_at(wx, wy, wz).unwrap_or(self.stone)
+                        };
+                        blocks[Chunk::get_index(cx, (wy - y) as usize, cz)] = block;
                     }
+                    covered = (covered + 1).min(biome.filler_depth + 1);
                 }
             }
         }
 
+        self.place_features(&mut blocks, x, y, z, heights);
+
         return blocks;
     }
 }
+
+impl Generator for NoiseGenerator {
+    fn sample_height(&self, x: i32, z: i32) -> i32 {
+        return self.noise_height(x, z);
+    }
+
+    fn biome_at(&self, x: i32, z: i32) -> Biome {
+        let (temperature, humidity) = self.climate(x, z);
+        return self.biomes.nearest(temperature, humidity).biome;
+    }
+
+    fn generate_blocks(&self, x: i32, y: i32, z: i32) -> [Block; CHUNK_VOLUME] {
+        return self.generate_with_heights(x, y, z, &|x, z| self.noise_height(x, z));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::registry;
+    use std::collections::HashSet;
+
+    fn flat_generator() -> NoiseGenerator {
+        let registry = registry();
+        let mut config = TerrainConfig::default();
+        config.stages = GeneratorStages {
+            overhangs: false,
+            cheese_caves: false,
+            worm_caves: false,
+        };
+        return NoiseGenerator::new(69, &registry, config);
+    }
+
+    fn biome_definition(generator: &NoiseGenerator, x: i32, z: i32) -> &BiomeDefinition {
+        let (temperature, humidity) = generator.climate(x, z);
+        return generator.biomes.nearest(temperature, humidity);
+    }
+
+    #[test]
+    fn every_biome_shows_up_somewhere() {
+        let generator = flat_generator();
+        let mut seen = HashSet::new();
+        for x in -200..200 {
+            for z in -200..200 {
+                seen.insert(generator.biome_at(x * 40, z * 40));
+            }
+        }
+        assert_eq!(seen.len(), 5, "{seen:?}");
+    }
+
+    #[test]
+    fn surface_blocks_follow_the_biome() {
+        let generator = flat_generator();
+        let side = CHUNK_SIDE as i32;
+        let (mut checked, mut matching) = (0, 0);
+
+        // one chunk every 40 chunks, at the height of its middle column
+        for i in -4..4 {
+            for j in -4..4 {
+                let (x, z) = (i * 40 * side, j * 40 * side);
+                let middle = generator.sample_height(x + side / 2, z + side / 2);
+                let y = (middle - 1).div_euclid(side) * side;
+                let blocks = generator.generate_blocks(x, y, z);
+
+                for cx in 0..side {
+                    for cz in 0..side {
+                        let height = generator.sample_height(x + cx, z + cz);
+                        // beaches are sand in every biome
+                        if height <= generator.config.sea_level + BEACH_HEIGHT || !(y..y + side).contains(&(height - 1)) {
+                            continue;
+                        }
+                        checked += 1;
+                        if blocks[Chunk::get_index(cx as usize, (height - 1 - y) as usize, cz as usize)] == biome_definition(&generator, x + cx, z + cz).surface {
+                            matching += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        // trees and boulders cover a few columns
+        assert!(checked > 10000, "only {checked} columns were checked");
+        assert!(matching * 100 >= checked * 99, "{matching} of {checked} columns have their biome's surface");
+    }
+}
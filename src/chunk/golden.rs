@@ -0,0 +1,176 @@
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+// the world for a seed has to stay the same between versions, otherwise unsaved chunks
+// come out different next to saved ones. these chunks are generated and meshed with the
+// default terrain and the world seed, and their hashes compared against the committed ones
+pub const GOLDEN_PATH: &str = "tests/golden/terrain.ron";
+const GOLDEN_CHUNKS: [(i32, i32, i32); 8] = [
+    // around spawn
+    (0, 0, 0),
+    (2, 0, -3),
+    (-1, -1, 2),
+    // caves cutting through coal, iron and gold veins, fully solid chunks would mesh to nothing
+    (3, -2, -5),
+    (-1, -3, 1),
+    // bedrock
+    (0, -4, 0),
+    // different biomes
+    (40, 0, -25),
+    (-60, 1, 70),
+];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GoldenChunk {
+    pub coords: (i32, i32, i32),
+    pub blocks: u64,
+    pub naive_mesh: u64,
+    pub greedy_mesh: u64,
+    pub greedy_faces: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GoldenFile {
+    seed: u32,
+    chunks: Vec<GoldenChunk>,
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    Serialize(ron::Error),
+    // the golden file was written for another seed or set of chunks
+    Outdated,
+    Mismatch(Vec<(GoldenChunk, GoldenChunk)>),
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenError::Io(e) => write!(f, "failed to access golden file: {e}"),
+            GoldenError::Parse(e) => write!(f, "failed to parse golden file: {e}"),
+            GoldenError::Serialize(e) => write!(f, "failed to write golden file: {e}"),
+            GoldenError::Outdated => write!(f, "golden file doesn't cover the current seed and chunks, bless it again"),
+            GoldenError::Mismatch(chunks) => {
+                write!(f, "{} chunks changed:", chunks.len())?;
+                for (expected, actual) in chunks {
+                    write!(f, "\n  {:?}: expected {expected:?}, got {actual:?}", expected.coords)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+// fnv-1a, unlike the std hasher it is the same on every platform and rust version
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> StableHasher {
+        return StableHasher(0xcbf29ce484222325);
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        for byte in value.to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn hash_blocks(blocks: &[Block; CHUNK_VOLUME]) -> u64 {
+    let mut hasher = StableHasher::new();
+    for block in blocks {
+        hasher.write_u32(block.get_id() as u32);
+    }
+    return hasher.0;
+}
+
+fn hash_mesh(mesh: &ChunkMesh) -> u64 {
+    let mut hasher = StableHasher::new();
+    for face in &mesh.faces {
+        for word in face.bits() {
+            hasher.write_u32(word);
+        }
+    }
+    return hasher.0;
+}
+
+// generates and meshes every golden chunk from scratch
+pub fn compute_golden(registry: &BlockRegistry) -> Vec<GoldenChunk> {
+    let generator = NoiseGenerator::new(SEED, registry, TerrainConfig::default());
+    let side = CHUNK_SIDE as i32;
+    let generate = |(x, y, z): (i32, i32, i32)| generator.generate_blocks(x * side, y * side, z * side);
+
+    return GOLDEN_CHUNKS
+        .iter()
+        .map(|&coords| {
+            let blocks = generate(coords);
+            let chunk = Chunk::from_blocks(&blocks);
+            let (x, y, z) = coords;
+            let around = neighbour_offsets().map(|(dx, dy, dz)| Chunk::from_blocks(&generate((x + dx, y + dy, z + dz))));
+            let neighbours = || Neighbours::new(std::array::from_fn(|i| Some(&around[i])));
+
+            let naive = mesh(&chunk, neighbours(), registry, MeshingMode::Naive);
+            let greedy = mesh(&chunk, neighbours(), registry, MeshingMode::Greedy);
+            return GoldenChunk {
+                coords,
+                blocks: hash_blocks(&blocks),
+                naive_mesh: hash_mesh(&naive),
+                greedy_mesh: hash_mesh(&greedy),
+                greedy_faces: greedy.faces.len(),
+            };
+        })
+        .collect();
+}
+
+pub fn check_golden(path: impl AsRef<Path>, registry: &BlockRegistry) -> Result<(), GoldenError> {
+    let src = std::fs::read_to_string(path).map_err(GoldenError::Io)?;
+    let file: GoldenFile = ron::from_str(&src).map_err(GoldenError::Parse)?;
+    let actual = compute_golden(registry);
+
+    let same_chunks = file.chunks.len() == actual.len() && file.chunks.iter().zip(&actual).all(|(e, a)| e.coords == a.coords);
+    if file.seed != SEED || !same_chunks {
+        return Err(GoldenError::Outdated);
+    }
+
+    let mismatches: Vec<(GoldenChunk, GoldenChunk)> = file.chunks.into_iter().zip(actual).filter(|(e, a)| e != a).collect();
+    if !mismatches.is_empty() {
+        return Err(GoldenError::Mismatch(mismatches));
+    }
+    return Ok(());
+}
+
+// overwrites the golden file with the current output, for when the terrain is meant to change
+pub fn bless_golden(path: impl AsRef<Path>, registry: &BlockRegistry) -> Result<(), GoldenError> {
+    let file = GoldenFile { seed: SEED, chunks: compute_golden(registry) };
+    let header = "// generated with `cargo run --release -- --bless-golden`, don't edit by hand\n";
+    let src = ron::ser::to_string_pretty(&file, ron::ser::PrettyConfig::default()).map_err(GoldenError::Serialize)?;
+    if let Some(dir) = path.as_ref().parent() {
+        std::fs::create_dir_all(dir).map_err(GoldenError::Io)?;
+    }
+    std::fs::write(path, format!("{header}{src}\n")).map_err(GoldenError::Io)?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::registry;
+
+    // same as `--check-golden`, run `--bless-golden` when the change is intended
+    #[test]
+    fn world_generation_matches_the_golden_file() {
+        let registry = registry();
+        if let Err(e) = check_golden(GOLDEN_PATH, &registry) {
+            panic!("{e}");
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use super::*;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// what the terrain does past the edges of the image
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum HeightmapEdges {
+    // repeats the image forever
+    Tile,
+    // stretches the border pixels outwards
+    Clamp,
+    // fades into the noise terrain
+    Noise,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeightmapConfig {
+    // grayscale or color png, 8 or 16 bits per channel
+    pub path: String,
+    // world x and z of the top left pixel
+    pub origin: (i32, i32),
+    // blocks per pixel
+    pub scale: f64,
+    // terrain heights of black and white pixels
+    pub min_height: f64,
+    pub max_height: f64,
+    pub edges: HeightmapEdges,
+    // with noise edges, how many blocks it takes to fade from the image into the noise
+    pub blend: f64,
+}
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(std::io::Error),
+    Decode(png::DecodingError),
+    Empty,
+    // blocks per pixel has to be a positive number
+    InvalidScale(f64),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(e) => write!(f, "failed to read heightmap: {e}"),
+            HeightmapError::Decode(e) => write!(f, "failed to decode heightmap: {e}"),
+            HeightmapError::Empty => write!(f, "heightmap has no pixels"),
+            HeightmapError::InvalidScale(scale) => write!(f, "heightmap scale has to be positive, got {scale}"),
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+// the image brightness of every pixel, from 0 to 1
+pub struct Heightmap {
+    width: usize,
+    height: usize,
+    values: Vec<f64>,
+}
+
+impl Heightmap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Heightmap, HeightmapError> {
+        let file = File::open(path).map_err(HeightmapError::Io)?;
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        // palettes and 1, 2 or 4 bit images come out as 8 bits per channel, 16 bit ones stay 16 bit
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().map_err(HeightmapError::Decode)?;
+        let mut buffer = vec![0; reader.output_buffer_size().ok_or(HeightmapError::Empty)?];
+        let info = reader.next_frame(&mut buffer).map_err(HeightmapError::Decode)?;
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        if width == 0 || height == 0 {
+            return Err(HeightmapError::Empty);
+        }
+
+        let wide = info.bit_depth == png::BitDepth::Sixteen;
+        let sample_size = if wide { 2 } else { 1 };
+        let max = if wide { u16::MAX as f64 } else { u8::MAX as f64 };
+        let samples = info.color_type.samples();
+        // alpha is ignored, color images use the average of their channels
+        let channels = match info.color_type {
+            png::ColorType::GrayscaleAlpha | png::ColorType::Rgba => samples - 1,
+            _ => samples,
+        };
+
+        let mut values = Vec::with_capacity(width * height);
+        for row in buffer.chunks(info.line_size).take(height) {
+            for pixel in row.chunks(samples * sample_size).take(width) {
+                let sum: f64 = pixel.chunks(sample_size).take(channels).map(|s| if wide { u16::from_be_bytes([s[0], s[1]]) as f64 } else { s[0] as f64 }).sum();
+                values.push(sum / (channels as f64 * max));
+            }
+        }
+
+        return Ok(Heightmap { width, height, values });
+    }
+
+    fn pixel(&self, x: i64, z: i64, tile: bool) -> f64 {
+        let (w, h) = (self.width as i64, self.height as i64);
+        let (x, z) = if tile { (x.rem_euclid(w), z.rem_euclid(h)) } else { (x.clamp(0, w - 1), z.clamp(0, h - 1)) };
+        return self.values[z as usize * self.width + x as usize];
+    }
+
+    // bilinear between the four closest pixels, x and z are in pixels
+    pub fn sample(&self, x: f64, z: f64, tile: bool) -> f64 {
+        let (x0, z0) = (x.floor(), z.floor());
+        let (fx, fz) = (x - x0, z - z0);
+        let (x0, z0) = (x0 as i64, z0 as i64);
+
+        let top = self.pixel(x0, z0, tile) * (1.0 - fx) + self.pixel(x0 + 1, z0, tile) * fx;
+        let bottom = self.pixel(x0, z0 + 1, tile) * (1.0 - fx) + self.pixel(x0 + 1, z0 + 1, tile) * fx;
+        return top * (1.0 - fz) + bottom * fz;
+    }
+
+    // how far outside the image the point is, 0 inside it
+    fn distance_outside(&self, x: f64, z: f64) -> f64 {
+        let dx = (-x).max(x - (self.width - 1) as f64).max(0.0);
+        let dz = (-z).max(z - (self.height - 1) as f64).max(0.0);
+        return (dx * dx + dz * dz).sqrt();
+    }
+}
+
+// terrain heights from an image, everything else (biomes, caves, ores, features) comes from the noise generator
+pub struct HeightmapGenerator {
+    config: HeightmapConfig,
+    map: Heightmap,
+    noise: NoiseGenerator,
+}
+
+impl HeightmapGenerator {
+    pub fn new(config: HeightmapConfig, noise: NoiseGenerator) -> Result<HeightmapGenerator, HeightmapError> {
+        if !config.scale.is_finite() || config.scale <= 0.0 {
+            return Err(HeightmapError::InvalidScale(config.scale));
+        }
+        let map = Heightmap::load(&config.path)?;
+        return Ok(HeightmapGenerator { config, map, noise });
+    }
+
+    fn height(&self, x: i32, z: i32) -> i32 {
+        let config = &self.config;
+        let px = (x - config.origin.0) as f64 / config.scale;
+        let pz = (z - config.origin.1) as f64 / config.scale;
+
+        let value = self.map.sample(px, pz, config.edges == HeightmapEdges::Tile);
+        let mut height = config.min_height + (config.max_height - config.min_height) * value;
+
+        if config.edges == HeightmapEdges::Noise {
+            let outside = self.map.distance_outside(px, pz) * config.scale;
+            if outside > 0.0 {
+                let t = if config.blend > 0.0 { (outside / config.blend).min(1.0) } else { 1.0 };
+                height += (self.noise.noise_height(x, z) as f64 - height) * t;
+            }
+        }
+        return height.round() as i32;
+    }
+}
+
+impl Generator for HeightmapGenerator {
+    fn sample_height(&self, x: i32, z: i32) -> i32 {
+        return self.height(x, z);
+    }
+
+    fn biome_at(&self, x: i32, z: i32) -> Biome {
+        return self.noise.biome_at(x, z);
+    }
+
+    fn generate_blocks(&self, x: i32, y: i32, z: i32) -> [Block; CHUNK_VOLUME] {
+        return self.noise.generate_with_heights(x, y, z, &|x, z| self.height(x, z));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::registry;
+
+    // 3 by 2 pixels:
+    // 0.0 0.5 1.0
+    // 0.2 0.4 0.6
+    fn tiny_map() -> Heightmap {
+        return Heightmap {
+            width: 3,
+            height: 2,
+            values: vec![0.0, 0.5, 1.0, 0.2, 0.4, 0.6],
+        };
+    }
+
+    fn config(edges: HeightmapEdges) -> HeightmapConfig {
+        return HeightmapConfig {
+            path: "unused.png".to_string(),
+            origin: (10, -10),
+            scale: 4.0,
+            min_height: 0.0,
+            max_height: 100.0,
+            edges,
+            blend: 40.0,
+        };
+    }
+
+    fn noise() -> NoiseGenerator {
+        let registry = registry();
+        return NoiseGenerator::new(SEED, &registry, TerrainConfig::default());
+    }
+
+    fn generator(edges: HeightmapEdges) -> HeightmapGenerator {
+        return HeightmapGenerator {
+            config: config(edges),
+            map: tiny_map(),
+            noise: noise(),
+        };
+    }
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn samples_blend_between_pixels() {
+        let map = tiny_map();
+        assert_near(map.sample(0.0, 0.0, false), 0.0);
+        assert_near(map.sample(2.0, 1.0, false), 0.6);
+        assert_near(map.sample(0.5, 0.0, false), 0.25);
+        assert_near(map.sample(1.0, 0.5, false), 0.45);
+        assert_near(map.sample(0.5, 0.5, false), 0.275);
+    }
+
+    #[test]
+    fn clamped_edges_repeat_the_border_pixels() {
+        let map = tiny_map();
+        assert_near(map.sample(-5.0, 0.0, false), 0.0);
+        assert_near(map.sample(-5.0, 7.0, false), 0.2);
+        assert_near(map.sample(9.0, -3.0, false), 1.0);
+        assert_near(map.sample(9.0, 1.5, false), 0.6);
+        // past the last pixel there is nothing left to blend with
+        assert_near(map.sample(2.5, 0.0, false), 1.0);
+    }
+
+    #[test]
+    fn tiled_edges_wrap_around() {
+        let map = tiny_map();
+        assert_near(map.sample(3.0, 0.0, true), 0.0);
+        assert_near(map.sample(-1.0, 0.0, true), 1.0);
+        assert_near(map.sample(-3.0, -2.0, true), 0.0);
+        assert_near(map.sample(4.0, 3.0, true), 0.4);
+        // the last pixel blends into the first one
+        assert_near(map.sample(2.5, 0.0, true), 0.5);
+    }
+
+    #[test]
+    fn distance_outside_is_zero_inside_the_image() {
+        let map = tiny_map();
+        assert_near(map.distance_outside(0.0, 0.0), 0.0);
+        assert_near(map.distance_outside(2.0, 1.0), 0.0);
+        assert_near(map.distance_outside(-3.0, 0.5), 3.0);
+        assert_near(map.distance_outside(5.0, 5.0), 5.0);
+    }
+
+    #[test]
+    fn heights_scale_the_image() {
+        for edges in [HeightmapEdges::Tile, HeightmapEdges::Clamp, HeightmapEdges::Noise] {
+            let generator = generator(edges);
+            // pixel (2, 1) is 8 and 4 blocks from the origin
+            assert_eq!(generator.sample_height(18, -6), 60);
+            assert_eq!(generator.sample_height(12, -10), 25);
+        }
+
+        assert_eq!(generator(HeightmapEdges::Clamp).sample_height(-1000, -1000), 0);
+        assert_eq!(generator(HeightmapEdges::Tile).sample_height(10 + 12 * 5, -10 + 8 * 3), 0);
+    }
+
+    #[test]
+    fn noise_edges_fade_into_the_noise_terrain() {
+        let generator = generator(HeightmapEdges::Noise);
+        let noise = noise();
+
+        // the right edge of the image is x = 18, the blend ends 40 blocks further
+        for x in [58, 70, 500] {
+            assert_eq!(generator.sample_height(x, -6), noise.noise_height(x, -6));
+        }
+
+        let image = 60.0;
+        let halfway = noise.noise_height(38, -6) as f64;
+        assert_eq!(generator.sample_height(38, -6), (image + (halfway - image) * 0.5).round() as i32);
+    }
+
+    #[test]
+    fn scales_that_arent_positive_are_rejected() {
+        for scale in [0.0, -2.0, f64::NAN, f64::INFINITY] {
+            let config = HeightmapConfig { scale, ..config(HeightmapEdges::Clamp) };
+            assert!(matches!(HeightmapGenerator::new(config, noise()), Err(HeightmapError::InvalidScale(_))));
+        }
+    }
+}
@@ -1,38 +1,40 @@
-use super::{Block, Face, chunk::*};
+use super::{Block, BlockRegistry, Face, chunk::*};
 
+// the 26 chunks around the one being meshed, indexed by neighbour_index. faces and ao on the
+// border of the chunk read from them, including the edge and corner ones. missing chunks read as air
 pub struct Neighbours<'a> {
-    pub xp: Option<&'a [Block; CHUNK_VOLUME]>,
-    pub xn: Option<&'a [Block; CHUNK_VOLUME]>,
-    pub yp: Option<&'a [Block; CHUNK_VOLUME]>,
-    pub yn: Option<&'a [Block; CHUNK_VOLUME]>,
-    pub zp: Option<&'a [Block; CHUNK_VOLUME]>,
-    pub zn: Option<&'a [Block; CHUNK_VOLUME]>,
+    chunks: [Option<&'a Chunk>; 27],
+}
+
+// the center chunk is at 13
+pub const fn neighbour_index(dx: i32, dy: i32, dz: i32) -> usize {
+    return ((dx + 1) + (dy + 1) * 3 + (dz + 1) * 9) as usize;
+}
+
+// the chunk offset of every neighbour_index
+pub fn neighbour_offsets() -> [(i32, i32, i32); 27] {
+    return std::array::from_fn(|i| (i as i32 % 3 - 1, i as i32 / 3 % 3 - 1, i as i32 / 9 - 1));
+}
+
+impl<'a> Neighbours<'a> {
+    // in neighbour_index order, the center entry isn't read
+    pub fn new(chunks: [Option<&'a Chunk>; 27]) -> Self {
+        return Neighbours { chunks };
+    }
 }
 
 #[inline]
 fn get_block(center: &[Block; CHUNK_VOLUME], neigh: &Neighbours, x: i32, y: i32, z: i32) -> Block {
-    if x >= 0 && x < CHUNK_SIDE as i32 && y >= 0 && y < CHUNK_SIDE as i32 && z >= 0 && z < CHUNK_SIDE as i32 {
+    let side = CHUNK_SIDE as i32;
+    if x >= 0 && x < side && y >= 0 && y < side && z >= 0 && z < side {
         return center[Chunk::get_index(x as usize, y as usize, z as usize)];
     }
 
-    let res = if x < 0 {
-        neigh.xn.map(|c| c[Chunk::get_index(CHUNK_SIDE - 1, y as usize, z as usize)])
-    } else if x >= CHUNK_SIDE as i32 {
-        neigh.xp.map(|c| c[Chunk::get_index(0, y as usize, z as usize)])
-    } else if y < 0 {
-        neigh.yn.map(|c| c[Chunk::get_index(x as usize, CHUNK_SIDE - 1, z as usize)])
-    } else if y >= CHUNK_SIDE as i32 {
-        neigh.yp.map(|c| c[Chunk::get_index(x as usize, 0, z as usize)])
-    } else if z < 0 {
-        neigh.zn.map(|c| c[Chunk::get_index(x as usize, y as usize, CHUNK_SIDE - 1)])
-    } else if z >= CHUNK_SIDE as i32 {
-        neigh.zp.map(|c| c[Chunk::get_index(x as usize, y as usize, 0)])
-    } else {
-        None
-    };
-
-    return res.unwrap_or(Block::AIR);
+    let chunk = neigh.chunks[neighbour_index(x.div_euclid(side), y.div_euclid(side), z.div_euclid(side))];
+    let index = Chunk::get_index(x.rem_euclid(side) as usize, y.rem_euclid(side) as usize, z.rem_euclid(side) as usize);
+    return chunk.map(|c| c.get(index)).unwrap_or(Block::AIR);
 }
+
 const FACES: [(i32, i32, i32); 6] = [
     (1, 0, 0),  // +X
     (-1, 0, 0), // -X
@@ -49,7 +51,93 @@ const FACES: [(i32, i32, i32); 6] = [
 // 4 -> +z
 // 5 -> -Z
 
-pub fn mesh(blocks: &[Block; CHUNK_VOLUME], neigh: Neighbours) -> ChunkMesh {
+// has to match corner_offsets in vert.slang
+const CORNERS: [[(i32, i32, i32); 4]; 6] = [
+    [(1, 0, 0), (1, 1, 0), (1, 1, 1), (1, 0, 1)],
+    [(0, 0, 1), (0, 1, 1), (0, 1, 0), (0, 0, 0)],
+    [(0, 1, 0), (0, 1, 1), (1, 1, 1), (1, 1, 0)],
+    [(0, 0, 1), (0, 0, 0), (1, 0, 0), (1, 0, 1)],
+    [(0, 0, 1), (1, 0, 1), (1, 1, 1), (0, 1, 1)],
+    [(0, 0, 0), (0, 1, 0), (1, 1, 0), (1, 0, 0)],
+];
+
+// the two axes spanned by a face, the first one is the width axis and the second the height axis.
+#[inline]
+const fn face_axes(normal_axis: usize) -> (usize, usize) {
+    return match normal_axis {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+}
+
+// faces between two of the same transparent block (e.g. water) are hidden
+#[inline]
+fn is_face_visible(registry: &BlockRegistry, mat: Block, neighbour: Block) -> bool {
+    return !mat.is_air() && neighbour != mat && registry.is_transparent(neighbour);
+}
+
+// 0 is fully occluded, 3 is not occluded at all.
+#[inline]
+pub fn vertex_ao(side1: bool, side2: bool, corner: bool) -> u32 {
+    if side1 && side2 {
+        return 0;
+    }
+    return 3 - (side1 as u32 + side2 as u32 + corner as u32);
+}
+
+// ao of the four corners of a face, in the same order as CORNERS.
+// looks at the 3x3 blocks in front of the face.
+pub fn face_ao(blocks: &[Block; CHUNK_VOLUME], neigh: &Neighbours, registry: &BlockRegistry, x: i32, y: i32, z: i32, normal: usize) -> [u32; 4] {
+    let (nx, ny, nz) = FACES[normal];
+    let (fx, fy, fz) = (x + nx, y + ny, z + nz);
+    let axis = normal / 2;
+    let (u_axis, v_axis) = face_axes(axis);
+
+    let solid = |offset: [i32; 3]| -> bool { registry.is_opaque(get_block(blocks, neigh, fx + offset[0], fy + offset[1], fz + offset[2])) };
+
+    let mut ao = [0; 4];
+    for (i, corner) in CORNERS[normal].iter().enumerate() {
+        let corner = [corner.0, corner.1, corner.2];
+        let mut du = [0; 3];
+        let mut dv = [0; 3];
+        du[u_axis] = corner[u_axis] * 2 - 1;
+        dv[v_axis] = corner[v_axis] * 2 - 1;
+
+        let side1 = solid(du);
+        let side2 = solid(dv);
+        let diagonal = solid([du[0] + dv[0], du[1] + dv[1], du[2] + dv[2]]);
+        ao[i] = vertex_ao(side1, side2, diagonal);
+    }
+
+    return ao;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MeshingMode {
+    // one face per exposed block side
+    Naive,
+    // coplanar faces of the same block are merged into larger quads
+    Greedy,
+}
+
+pub fn mesh(chunk: &Chunk, neigh: Neighbours, registry: &BlockRegistry, mode: MeshingMode) -> ChunkMesh {
+    // all air, nothing to mesh
+    if chunk.is_uniform() && chunk.palette()[0].is_air() {
+        return ChunkMesh { faces: Vec::new() };
+    }
+
+    // the center chunk is read a lot, so unpack it once
+    let mut blocks = Box::new([Block::AIR; CHUNK_VOLUME]);
+    chunk.unpack_into(&mut blocks);
+
+    return match mode {
+        MeshingMode::Naive => mesh_naive(&blocks, neigh, registry),
+        MeshingMode::Greedy => mesh_greedy(&blocks, neigh, registry),
+    };
+}
+
+pub fn mesh_naive(blocks: &[Block; CHUNK_VOLUME], neigh: Neighbours, registry: &BlockRegistry) -> ChunkMesh {
     let mut vertices: Vec<Face> = Vec::new();
 
     for z in 0..CHUNK_SIDE {
@@ -68,8 +156,9 @@ pub fn mesh(blocks: &[Block; CHUNK_VOLUME], neigh: Neighbours) -> ChunkMesh {
 
                     let neighbor_mat = get_block(blocks, &neigh, nx, ny, nz);
 
-                    if neighbor_mat.is_air() {
-                        vertices.push(Face::new(x as u32, y as u32, z as u32, i as u32, mat));
+                    if is_face_visible(registry, mat, neighbor_mat) {
+                        let ao = face_ao(blocks, &neigh, registry, x as i32, y as i32, z as i32, i);
+                        vertices.push(Face::new(x as u32, y as u32, z as u32, i as u32, ao, mat));
                     }
                 }
             }
@@ -78,3 +167,225 @@ pub fn mesh(blocks: &[Block; CHUNK_VOLUME], neigh: Neighbours) -> ChunkMesh {
 
     return ChunkMesh { faces: vertices };
 }
+
+pub fn mesh_greedy(blocks: &[Block; CHUNK_VOLUME], neigh: Neighbours, registry: &BlockRegistry) -> ChunkMesh {
+    let mut vertices: Vec<Face> = Vec::new();
+    // faces can only be merged if both the block and the ao of every corner match
+    let mut mask = [(Block::AIR, [0; 4]); CHUNK_SIDE * CHUNK_SIDE];
+
+    for (i, (dx, dy, dz)) in FACES.iter().enumerate() {
+        let axis = i / 2;
+        let (u_axis, v_axis) = face_axes(axis);
+
+        for slice in 0..CHUNK_SIDE {
+            // build the mask of exposed faces in this slice
+            for v in 0..CHUNK_SIDE {
+                for u in 0..CHUNK_SIDE {
+                    let mut pos = [0; 3];
+                    pos[axis] = slice;
+                    pos[u_axis] = u;
+                    pos[v_axis] = v;
+
+                    let mat = blocks[Chunk::get_index(pos[0], pos[1], pos[2])];
+                    let exposed = is_face_visible(registry, mat, get_block(blocks, &neigh, pos[0] as i32 + dx, pos[1] as i32 + dy, pos[2] as i32 + dz));
+
+                    mask[u + v * CHUNK_SIDE] = if exposed {
+                        (mat, face_ao(blocks, &neigh, registry, pos[0] as i32, pos[1] as i32, pos[2] as i32, i))
+                    } else {
+                        (Block::AIR, [0; 4])
+                    };
+                }
+            }
+
+            // merge the mask into quads
+            for v in 0..CHUNK_SIDE {
+                let mut u = 0;
+                while u < CHUNK_SIDE {
+                    let key = mask[u + v * CHUNK_SIDE];
+                    if key.0.is_air() {
+                        u += 1;
+                        continue;
+                    }
+
+                    let mut width = 1;
+                    while u + width < CHUNK_SIDE && mask[u + width + v * CHUNK_SIDE] == key {
+                        width += 1;
+                    }
+
+                    let mut height = 1;
+                    'grow: while v + height < CHUNK_SIDE {
+                        for k in 0..width {
+                            if mask[u + k + (v + height) * CHUNK_SIDE] != key {
+                                break 'grow;
+                            }
+                        }
+                        height += 1;
+                    }
+
+                    for h in 0..height {
+                        for k in 0..width {
+                            mask[u + k + (v + h) * CHUNK_SIDE] = (Block::AIR, [0; 4]);
+                        }
+                    }
+
+                    let mut pos = [0; 3];
+                    pos[axis] = slice as u32;
+                    pos[u_axis] = u as u32;
+                    pos[v_axis] = v as u32;
+                    vertices.push(Face::new_quad(pos[0], pos[1], pos[2], i as u32, width as u32, height as u32, key.1, key.0));
+
+                    u += width;
+                }
+            }
+        }
+    }
+
+    return ChunkMesh { faces: vertices };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{Rng, registry};
+    use std::collections::HashSet;
+
+    // noise, terrain-like layers and solid blocks with a few holes, so greedy has something to merge
+    fn random_chunk(rng: &mut Rng, registry: &BlockRegistry) -> Chunk {
+        let palette = [Block::AIR, registry.get("grass").unwrap(), registry.get("dirt").unwrap(), registry.get("water").unwrap(), registry.get("sand").unwrap()];
+        let kind = rng.next() % 3;
+        let density = rng.next() % 100;
+        let mut blocks = [Block::AIR; CHUNK_VOLUME];
+        for z in 0..CHUNK_SIDE {
+            for y in 0..CHUNK_SIDE {
+                for x in 0..CHUNK_SIDE {
+                    let block = match kind {
+                        0 => palette[(rng.next() % palette.len() as u64) as usize],
+                        1 if y < (x + z) / 2 => palette[1 + y / 8 % 4],
+                        1 => Block::AIR,
+                        _ if rng.chance(density / 10) => Block::AIR,
+                        _ => palette[2],
+                    };
+                    blocks[Chunk::get_index(x, y, z)] = block;
+                }
+            }
+        }
+        return Chunk::from_blocks(&blocks);
+    }
+
+    // every face as the unit quads it covers, with the ao of the quad and its block
+    fn unit_faces(mesh: &ChunkMesh) -> HashSet<([u32; 3], u32, u32, u32)> {
+        let mut covered = HashSet::new();
+        for face in &mesh.faces {
+            let [data, extra] = face.bits();
+            let pos = [data & 31, (data >> 5) & 31, (data >> 10) & 31];
+            let normal = (data >> 15) & 7;
+            let (width, height) = (((data >> 18) & 31) + 1, ((data >> 23) & 31) + 1);
+            let (u_axis, v_axis) = face_axes(normal as usize / 2);
+            for v in 0..height {
+                for u in 0..width {
+                    let mut unit = pos;
+                    unit[u_axis] += u;
+                    unit[v_axis] += v;
+                    assert!(unit.iter().all(|&c| c < CHUNK_SIDE as u32), "quad leaves the chunk");
+                    assert!(covered.insert((unit, normal, extra & 0xFFFF, extra >> 16)), "quads overlap at {unit:?}");
+                }
+            }
+        }
+        return covered;
+    }
+
+    #[test]
+    fn greedy_covers_the_same_surface_as_naive() {
+        let registry = registry();
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..16 {
+            let chunk = random_chunk(&mut rng, &registry);
+            let around: Vec<Chunk> = (0..27).map(|_| random_chunk(&mut rng, &registry)).collect();
+            let with_neighbours = rng.chance(50);
+            let neighbours = || if with_neighbours { Neighbours::new(std::array::from_fn(|i| Some(&around[i]))) } else { Neighbours::new([None; 27]) };
+
+            let naive = mesh(&chunk, neighbours(), &registry, MeshingMode::Naive);
+            let greedy = mesh(&chunk, neighbours(), &registry, MeshingMode::Greedy);
+            assert!(greedy.faces.len() <= naive.faces.len());
+            assert_eq!(unit_faces(&naive), unit_faces(&greedy));
+        }
+    }
+
+    #[test]
+    fn greedy_merges_a_solid_chunk_into_six_quads() {
+        let registry = registry();
+        let chunk = Chunk::from_blocks(&[registry.get("dirt").unwrap(); CHUNK_VOLUME]);
+        assert_eq!(mesh(&chunk, Neighbours::new([None; 27]), &registry, MeshingMode::Greedy).faces.len(), 6);
+        assert_eq!(mesh(&chunk, Neighbours::new([None; 27]), &registry, MeshingMode::Naive).faces.len(), 6 * CHUNK_SIDE * CHUNK_SIDE);
+    }
+
+    #[test]
+    fn vertex_ao_counts_the_occluding_blocks() {
+        assert_eq!(vertex_ao(false, false, false), 3);
+        assert_eq!(vertex_ao(false, false, true), 2);
+        assert_eq!(vertex_ao(true, false, false), 2);
+        assert_eq!(vertex_ao(true, false, true), 1);
+        assert_eq!(vertex_ao(false, true, true), 1);
+        // both sides hide the corner whether it is there or not
+        assert_eq!(vertex_ao(true, true, false), 0);
+        assert_eq!(vertex_ao(true, true, true), 0);
+    }
+
+    #[test]
+    fn face_ao_darkens_the_corners_next_to_blocks() {
+        let registry = registry();
+        let dirt = registry.get("dirt").unwrap();
+        let water = registry.get("water").unwrap();
+        let mut blocks = [Block::AIR; CHUNK_VOLUME];
+        blocks[Chunk::get_index(5, 5, 5)] = dirt;
+        let top = |blocks: &[Block; CHUNK_VOLUME]| face_ao(blocks, &Neighbours::new([None; 27]), &registry, 5, 5, 5, 2);
+        assert_eq!(top(&blocks), [3, 3, 3, 3]);
+
+        // +Y corners are (0, 1, 0), (0, 1, 1), (1, 1, 1), (1, 1, 0), a block above and to +x shades the last two
+        blocks[Chunk::get_index(6, 6, 5)] = dirt;
+        assert_eq!(top(&blocks), [3, 3, 2, 2]);
+        // one above and to +z as well closes off the (1, 1, 1) corner
+        blocks[Chunk::get_index(5, 6, 6)] = dirt;
+        assert_eq!(top(&blocks), [3, 2, 0, 2]);
+        // only the diagonal block
+        blocks[Chunk::get_index(6, 6, 5)] = Block::AIR;
+        blocks[Chunk::get_index(5, 6, 6)] = Block::AIR;
+        blocks[Chunk::get_index(4, 6, 4)] = dirt;
+        assert_eq!(top(&blocks), [2, 3, 3, 3]);
+        // transparent blocks don't shade
+        blocks[Chunk::get_index(4, 6, 4)] = water;
+        assert_eq!(top(&blocks), [3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn face_ao_reads_edge_and_corner_neighbours() {
+        let registry = registry();
+        let dirt = registry.get("dirt").unwrap();
+        let mut blocks = [Block::AIR; CHUNK_VOLUME];
+        blocks[Chunk::get_index(31, 31, 31)] = dirt;
+        let last = CHUNK_SIDE - 1;
+
+        // the +Y face of the corner block looks into the chunks above it. the block to its +x is
+        // across the xy edge, the one diagonal to its (1, 1, 1) corner across the xyz corner
+        let mut edge_x = [Block::AIR; CHUNK_VOLUME];
+        edge_x[Chunk::get_index(0, 0, last)] = dirt;
+        let mut corner = [Block::AIR; CHUNK_VOLUME];
+        corner[Chunk::get_index(0, 0, 0)] = dirt;
+        let (edge_x, corner) = (Chunk::from_blocks(&edge_x), Chunk::from_blocks(&corner));
+
+        let mut around = [None; 27];
+        around[neighbour_index(1, 1, 0)] = Some(&edge_x);
+        assert_eq!(face_ao(&blocks, &Neighbours::new(around), &registry, 31, 31, 31, 2), [3, 3, 2, 2]);
+        around[neighbour_index(1, 1, 0)] = None;
+        around[neighbour_index(1, 1, 1)] = Some(&corner);
+        assert_eq!(face_ao(&blocks, &Neighbours::new(around), &registry, 31, 31, 31, 2), [3, 3, 2, 3]);
+    }
+
+    #[test]
+    fn neighbour_offsets_match_neighbour_index() {
+        for (i, (dx, dy, dz)) in neighbour_offsets().into_iter().enumerate() {
+            assert_eq!(neighbour_index(dx, dy, dz), i);
+        }
+        assert_eq!(neighbour_offsets()[neighbour_index(0, 0, 0)], (0, 0, 0));
+    }
+}
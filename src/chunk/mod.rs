@@ -1,6 +1,13 @@
+mod biome;
 mod block;
+mod caves;
 mod chunk;
+mod features;
 mod generator;
+mod golden;
+mod heightmap;
 mod mesher;
+mod registry;
+mod terrain_config;
 
-pub use {block::*, chunk::*, generator::*, mesher::*};
+pub use {biome::*, block::*, caves::*, chunk::*, features::*, generator::*, golden::*, heightmap::*, mesher::*, registry::*, terrain_config::*};
@@ -0,0 +1,221 @@
+use super::Block;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct FaceAppearance {
+    pub color: [f32; 4],
+    pub texture: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockFaces {
+    top: FaceAppearance,
+    bottom: FaceAppearance,
+    side: FaceAppearance,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockDescription {
+    name: String,
+    id: u16,
+    solid: bool,
+    transparent: bool,
+    faces: BlockFaces,
+    light: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockFile {
+    blocks: Vec<BlockDescription>,
+}
+
+pub const MAX_LIGHT: u8 = 15;
+
+pub struct BlockDefinition {
+    pub name: String,
+    pub block: Block,
+    pub solid: bool,
+    pub transparent: bool,
+    // indexed by face normal, same order as the mesher
+    pub faces: [FaceAppearance; 6],
+    pub light: u8,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    DuplicateId { id: u16, first: String, second: String },
+    DuplicateName(String),
+    InvalidAir,
+    InvalidLight { name: String, light: u8 },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Io(e) => write!(f, "failed to read block file: {e}"),
+            RegistryError::Parse(e) => write!(f, "failed to parse block file: {e}"),
+            RegistryError::DuplicateId { id, first, second } => write!(f, "blocks `{first}` and `{second}` both use id {id}"),
+            RegistryError::DuplicateName(name) => write!(f, "block `{name}` is defined more than once"),
+            RegistryError::InvalidAir => write!(f, "id 0 has to be a non solid, transparent block named `air`"),
+            RegistryError::InvalidLight { name, light } => write!(f, "block `{name}` has light level {light}, the maximum is {MAX_LIGHT}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+pub struct BlockRegistry {
+    // indexed by block id
+    definitions: Vec<Option<BlockDefinition>>,
+    names: HashMap<String, Block>,
+}
+
+impl BlockRegistry {
+    pub fn load(path: impl AsRef<Path>) -> Result<BlockRegistry, RegistryError> {
+        let src = std::fs::read_to_string(path).map_err(RegistryError::Io)?;
+        return BlockRegistry::parse(&src);
+    }
+
+    pub fn parse(src: &str) -> Result<BlockRegistry, RegistryError> {
+        let file: BlockFile = ron::from_str(src).map_err(RegistryError::Parse)?;
+
+        let mut definitions: Vec<Option<BlockDefinition>> = Vec::new();
+        let mut names = HashMap::new();
+
+        for desc in file.blocks {
+            let id = desc.id as usize;
+            if definitions.len() <= id {
+                definitions.resize_with(id + 1, || None);
+            }
+
+            if let Some(existing) = &definitions[id] {
+                return Err(RegistryError::DuplicateId {
+                    id: desc.id,
+                    first: existing.name.clone(),
+                    second: desc.name,
+                });
+            }
+            if names.contains_key(&desc.name) {
+                return Err(RegistryError::DuplicateName(desc.name));
+            }
+            if desc.light > MAX_LIGHT {
+                return Err(RegistryError::InvalidLight { name: desc.name, light: desc.light });
+            }
+
+            let block = Block::new(desc.id);
+            let faces = desc.faces;
+            names.insert(desc.name.clone(), block);
+            definitions[id] = Some(BlockDefinition {
+                name: desc.name,
+                block,
+                solid: desc.solid,
+                transparent: desc.transparent,
+                faces: [faces.side, faces.side, faces.top, faces.bottom, faces.side, faces.side],
+                light: desc.light,
+            });
+        }
+
+        match definitions.first() {
+            Some(Some(air)) if air.name == "air" && !air.solid && air.transparent => {}
+            _ => return Err(RegistryError::InvalidAir),
+        }
+
+        return Ok(BlockRegistry { definitions, names });
+    }
+
+    pub fn get(&self, name: &str) -> Option<Block> {
+        return self.names.get(name).copied();
+    }
+
+    #[inline]
+    pub fn definition(&self, block: Block) -> Option<&BlockDefinition> {
+        return self.definitions.get(block.get_id() as usize).and_then(|d| d.as_ref());
+    }
+
+    // blocks that hide the faces behind them and cast ambient occlusion
+    #[inline]
+    pub fn is_opaque(&self, block: Block) -> bool {
+        return self.definition(block).is_some_and(|d| d.solid && !d.transparent);
+    }
+
+    #[inline]
+    pub fn is_transparent(&self, block: Block) -> bool {
+        return self.definition(block).is_none_or(|d| d.transparent);
+    }
+
+    // one past the highest defined id
+    pub fn id_count(&self) -> usize {
+        return self.definitions.len();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockDefinition> {
+        return self.definitions.iter().flatten();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, id: u16, solid: bool, light: u8) -> String {
+        let face = "(color: (1.0, 1.0, 1.0, 1.0), texture: 0)";
+        return format!("(name: \"{name}\", id: {id}, solid: {solid}, transparent: {}, faces: (top: {face}, bottom: {face}, side: {face}), light: {light}),", !solid);
+    }
+
+    fn parse(entries: &[String]) -> Result<BlockRegistry, RegistryError> {
+        return BlockRegistry::parse(&format!("(blocks: [{}])", entries.concat()));
+    }
+
+    #[test]
+    fn blocks_are_found_by_name() {
+        let registry = parse(&[entry("air", 0, false, 0), entry("stone", 3, true, 0), entry("lamp", 1, true, 15)]).unwrap_or_else(|e| panic!("{e}"));
+        let stone = registry.get("stone").unwrap();
+        assert_eq!(stone.get_id(), 3);
+        assert!(registry.definition(stone).is_some_and(|d| d.name == "stone" && d.solid));
+        assert!(registry.is_opaque(stone));
+        assert!(registry.get("dirt").is_none());
+        // the gap at 2 is undefined
+        assert_eq!(registry.id_count(), 4);
+        assert!(registry.definition(Block::new(2)).is_none());
+    }
+
+    #[test]
+    fn duplicate_ids_and_names_are_rejected() {
+        let result = parse(&[entry("air", 0, false, 0), entry("stone", 1, true, 0), entry("dirt", 1, true, 0)]);
+        assert!(matches!(result, Err(RegistryError::DuplicateId { id: 1, ref first, ref second }) if first == "stone" && second == "dirt"));
+
+        let result = parse(&[entry("air", 0, false, 0), entry("stone", 1, true, 0), entry("stone", 2, true, 0)]);
+        assert!(matches!(result, Err(RegistryError::DuplicateName(ref name)) if name == "stone"));
+    }
+
+    #[test]
+    fn missing_and_unknown_fields_are_parse_errors() {
+        let without_solid = entry("stone", 1, true, 0).replace("solid: true, ", "");
+        assert!(matches!(parse(&[entry("air", 0, false, 0), without_solid]), Err(RegistryError::Parse(_))));
+
+        let extra = entry("stone", 1, true, 0).replace("solid: true", "solid: true, hardness: 2");
+        assert!(matches!(parse(&[entry("air", 0, false, 0), extra]), Err(RegistryError::Parse(_))));
+    }
+
+    #[test]
+    fn id_zero_has_to_be_air() {
+        assert!(matches!(parse(&[entry("stone", 1, true, 0)]), Err(RegistryError::InvalidAir)));
+        assert!(matches!(parse(&[entry("void", 0, false, 0)]), Err(RegistryError::InvalidAir)));
+        assert!(matches!(parse(&[entry("air", 0, true, 0)]), Err(RegistryError::InvalidAir)));
+    }
+
+    #[test]
+    fn light_above_the_maximum_is_rejected() {
+        let result = parse(&[entry("air", 0, false, 0), entry("lamp", 1, true, MAX_LIGHT + 1)]);
+        assert!(matches!(result, Err(RegistryError::InvalidLight { ref name, light: 16 }) if name == "lamp"));
+    }
+}
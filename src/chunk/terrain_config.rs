@@ -0,0 +1,252 @@
+use super::HeightmapConfig;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+// `--terrain <preset>` loads <preset>.ron from here, `--terrain <path>.ron` loads any file
+pub const TERRAIN_DIR: &str = "assets/terrain";
+
+// the generation passes that can be turned off, e.g. to test one of them on its own
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorStages {
+    pub overhangs: bool,
+    pub cheese_caves: bool,
+    pub worm_caves: bool,
+}
+
+impl Default for GeneratorStages {
+    fn default() -> Self {
+        return GeneratorStages {
+            overhangs: true,
+            cheese_caves: true,
+            worm_caves: true,
+        };
+    }
+}
+
+// how the octaves of the height noise are combined
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum NoiseMode {
+    // plain rolling hills
+    Fbm,
+    // sharp crests where the noise crosses zero, good for mountain ranges
+    Ridged,
+    // rounded lumps with creases in between
+    Billow,
+}
+
+// the fractal noise the heightmap is built from
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeightNoise {
+    pub mode: NoiseMode,
+    pub octaves: usize,
+    pub frequency: f64,
+    // how much the frequency grows with every octave
+    pub lacunarity: f64,
+    // how much the amplitude shrinks with every octave
+    pub persistence: f64,
+}
+
+impl Default for HeightNoise {
+    fn default() -> Self {
+        return HeightNoise {
+            mode: NoiseMode::Fbm,
+            octaves: 4,
+            frequency: 0.005,
+            lacunarity: 2.0,
+            persistence: 0.5,
+        };
+    }
+}
+
+// pushes the heightmap sample point around with another noise so the terrain looks less grid aligned
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DomainWarp {
+    // in blocks, 0 turns it off
+    pub strength: f64,
+    pub frequency: f64,
+}
+
+impl Default for DomainWarp {
+    fn default() -> Self {
+        return DomainWarp { strength: 0.0, frequency: 0.01 };
+    }
+}
+
+// blobs of ore scattered through the stone
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OreVein {
+    // registry name of the ore block
+    pub block: String,
+    // higher makes smaller, more frequent veins
+    pub frequency: f64,
+    // from -1 to 1, higher makes the veins rarer
+    pub threshold: f64,
+    pub min_y: i32,
+    pub max_y: i32,
+}
+
+#[derive(Debug)]
+pub enum TerrainConfigError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+}
+
+impl fmt::Display for TerrainConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainConfigError::Io(e) => write!(f, "failed to read terrain file: {e}"),
+            TerrainConfigError::Parse(e) => write!(f, "failed to parse terrain file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TerrainConfigError {}
+
+// everything that shapes the terrain, fields missing from a file keep their default
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TerrainConfig {
+    pub noise: HeightNoise,
+    pub domain_warp: DomainWarp,
+    // multiplies how far every biome's terrain reaches above and below its base height
+    pub height_scale: f64,
+    // puts every biome at this base height instead of its own, with a height scale of 0 the world is flat
+    pub base_height: Option<f64>,
+    // air below this is filled with water
+    pub sea_level: i32,
+    // everything at or below this is bedrock
+    pub bedrock_level: i32,
+    pub ores: Vec<OreVein>,
+    pub stages: GeneratorStages,
+    // takes the terrain height from an image instead of the noise
+    pub heightmap: Option<HeightmapConfig>,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        return TerrainConfig {
+            noise: HeightNoise::default(),
+            domain_warp: DomainWarp::default(),
+            height_scale: 1.0,
+            base_height: None,
+            sea_level: 2,
+            bedrock_level: -128,
+            ores: vec![
+                OreVein {
+                    block: "coal_ore".to_string(),
+                    frequency: 0.08,
+                    threshold: 0.55,
+                    min_y: -128,
+                    max_y: 64,
+                },
+                OreVein {
+                    block: "iron_ore".to_string(),
+                    frequency: 0.09,
+                    threshold: 0.58,
+                    min_y: -128,
+                    max_y: 16,
+                },
+                OreVein {
+                    block: "gold_ore".to_string(),
+                    frequency: 0.1,
+                    threshold: 0.62,
+                    min_y: -128,
+                    max_y: -32,
+                },
+            ],
+            stages: GeneratorStages::default(),
+            heightmap: None,
+        };
+    }
+}
+
+impl TerrainConfig {
+    // the file of a built in preset like `flat`, or the path itself for any .ron file
+    pub fn preset_path(preset: &str) -> String {
+        if preset.ends_with(".ron") {
+            return preset.to_string();
+        }
+        return format!("{TERRAIN_DIR}/{preset}.ron");
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<TerrainConfig, TerrainConfigError> {
+        let src = std::fs::read_to_string(path).map_err(TerrainConfigError::Io)?;
+        return TerrainConfig::parse(&src);
+    }
+
+    pub fn parse(src: &str) -> Result<TerrainConfig, TerrainConfigError> {
+        return ron::from_str(src).map_err(TerrainConfigError::Parse);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Generator, NoiseGenerator, SEED};
+    use crate::test_utils::{registry, temp_dir};
+
+    fn load_preset(preset: &str) -> TerrainConfig {
+        return TerrainConfig::load(TerrainConfig::preset_path(preset)).unwrap_or_else(|e| panic!("{preset}: {e}"));
+    }
+
+    #[test]
+    fn built_in_presets_load() {
+        assert_eq!(TerrainConfig::preset_path("flat"), "assets/terrain/flat.ron");
+        assert_eq!(TerrainConfig::preset_path("saves/mine.ron"), "saves/mine.ron");
+
+        let amplified = load_preset("amplified");
+        assert!(amplified.height_scale > 1.0);
+        let islands = load_preset("islands");
+        assert_eq!(islands.noise.mode, NoiseMode::Billow);
+        assert!(islands.sea_level > TerrainConfig::default().sea_level);
+        assert!(load_preset("crater_island").heightmap.is_some());
+    }
+
+    #[test]
+    fn the_flat_preset_is_flat() {
+        let generator = NoiseGenerator::new(SEED, &registry(), load_preset("flat"));
+        let height = generator.sample_height(0, 0);
+        for x in -50..50 {
+            for z in -50..50 {
+                assert_eq!(generator.sample_height(x * 97, z * 89), height);
+            }
+        }
+    }
+
+    #[test]
+    fn files_fill_in_the_fields_they_leave_out() {
+        let dir = temp_dir("terrain", "file");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("custom.ron");
+        let src = "(noise: (mode: Ridged, octaves: 6), domain_warp: (strength: 12.5), sea_level: -4, stages: (worm_caves: false), ores: [(block: \"coal_ore\", frequency: 0.1, threshold: 0.5, min_y: -10, max_y: 10)])";
+        std::fs::write(&path, src).unwrap();
+
+        let config = TerrainConfig::load(&path).unwrap();
+        let default = TerrainConfig::default();
+        assert_eq!(config.noise.mode, NoiseMode::Ridged);
+        assert_eq!(config.noise.octaves, 6);
+        assert_eq!(config.noise.frequency, default.noise.frequency);
+        assert_eq!(config.domain_warp.strength, 12.5);
+        assert_eq!(config.domain_warp.frequency, default.domain_warp.frequency);
+        assert_eq!(config.sea_level, -4);
+        assert_eq!(config.bedrock_level, default.bedrock_level);
+        assert!(!config.stages.worm_caves && config.stages.cheese_caves && config.stages.overhangs);
+        assert_eq!(config.ores.len(), 1);
+        assert_eq!(config.ores[0].block, "coal_ore");
+        assert!(config.heightmap.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_presets_and_fields_are_errors() {
+        assert!(matches!(TerrainConfig::load(TerrainConfig::preset_path("no_such_preset")), Err(TerrainConfigError::Io(_))));
+        assert!(matches!(TerrainConfig::parse("(height_scale: 2.0, mountains: true)"), Err(TerrainConfigError::Parse(_))));
+        assert!(matches!(TerrainConfig::parse("(noise: (mode: Wavy))"), Err(TerrainConfigError::Parse(_))));
+        assert!(matches!(TerrainConfig::parse("(sea_level: \"high\")"), Err(TerrainConfigError::Parse(_))));
+    }
+}
@@ -2,12 +2,36 @@ mod application;
 mod camera;
 mod chunk;
 mod renderer;
+#[cfg(test)]
+mod test_utils;
 mod world;
 
 use application::*;
+use chunk::{BlockRegistry, GOLDEN_PATH, bless_golden, check_golden};
 use winit::event_loop::EventLoop;
 
+// headless check that the world for the golden seed hasn't changed, returns the exit code.
+// `--bless-golden` records the current world instead, for when the change is intended
+fn run_golden(bless: bool) -> i32 {
+    let registry = BlockRegistry::load(BLOCKS_PATH).unwrap_or_else(|e| panic!("Failed to load {BLOCKS_PATH}: {e}"));
+    let result = if bless { bless_golden(GOLDEN_PATH, &registry) } else { check_golden(GOLDEN_PATH, &registry) };
+    match result {
+        Ok(()) if bless => println!("Wrote {GOLDEN_PATH}"),
+        Ok(()) => println!("World generation matches {GOLDEN_PATH}"),
+        Err(e) => {
+            eprintln!("Golden check failed: {e}");
+            return 1;
+        }
+    }
+    return 0;
+}
+
 fn main() {
+    let bless = std::env::args().any(|arg| arg == "--bless-golden");
+    if bless || std::env::args().any(|arg| arg == "--check-golden") {
+        std::process::exit(run_golden(bless));
+    }
+
     let mut runner = Runner::new();
     let event_loop = EventLoop::new().expect("Failed to create event loop");
 
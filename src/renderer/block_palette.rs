@@ -0,0 +1,71 @@
+use crate::chunk::{BlockRegistry, MAX_LIGHT};
+use sgpu::*;
+
+// has to match BlockData in vert.slang
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuBlock {
+    colors: [[f32; 4]; 6],
+    textures: [u32; 6],
+    light: f32,
+    _pad: u32,
+}
+
+impl GpuBlock {
+    fn zeroed() -> Self {
+        GpuBlock {
+            colors: [[0.0; 4]; 6],
+            textures: [0; 6],
+            light: 0.0,
+            _pad: 0,
+        }
+    }
+}
+
+// vkCmdUpdateBuffer can write at most 65536 bytes at once
+const UPLOAD_BLOCKS_PER_COPY: usize = 65536 / std::mem::size_of::<GpuBlock>();
+
+// per block id appearance, read by the vertex shader
+pub struct BlockPalette {
+    buffer: Buffer,
+}
+
+impl BlockPalette {
+    pub fn new(registry: &BlockRegistry) -> Self {
+        let mut blocks = vec![GpuBlock::zeroed(); registry.id_count()];
+        for def in registry.iter() {
+            blocks[def.block.get_id() as usize] = GpuBlock {
+                colors: def.faces.map(|f| f.color),
+                textures: def.faces.map(|f| f.texture),
+                light: def.light as f32 / MAX_LIGHT as f32,
+                _pad: 0,
+            };
+        }
+
+        let buffer = create_buffer(&BufferDescription {
+            size: (blocks.len() * std::mem::size_of::<GpuBlock>()) as u64,
+            usage: BufferUsage::STORAGE | BufferUsage::TRANSFER_DST,
+            memory_type: MemoryType::DeviceLocal,
+        });
+
+        // only done once at startup, so just wait for it
+        let mut cmd = record(QueueType::Transfer);
+        for (i, part) in blocks.chunks(UPLOAD_BLOCKS_PER_COPY).enumerate() {
+            cmd.update_buffer(&buffer, (i * UPLOAD_BLOCKS_PER_COPY * std::mem::size_of::<GpuBlock>()) as u64, part);
+        }
+        submit(&[cmd]);
+        wait_idle();
+
+        return BlockPalette { buffer };
+    }
+
+    pub fn raw(&self) -> Buffer {
+        self.buffer
+    }
+}
+
+impl Drop for BlockPalette {
+    fn drop(&mut self) {
+        destroy_buffer(self.buffer);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::chunk::CHUNK_SIDE;
+use sgpu::*;
+
+// has to match ChunkInfo in cull.slang
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ChunkInfo {
+    pub min: [f32; 4],
+    pub max: [f32; 4],
+}
+
+impl ChunkInfo {
+    pub fn from_coords(coords: (i32, i32, i32)) -> Self {
+        let side = CHUNK_SIDE as f32;
+        let min = [coords.0 as f32 * side, coords.1 as f32 * side, coords.2 as f32 * side];
+        ChunkInfo {
+            min: [min[0], min[1], min[2], 0.0],
+            max: [min[0] + side, min[1] + side, min[2] + side, 0.0],
+        }
+    }
+}
+
+// per chunk bounds used by the culling pass, indexed by the same slots as the IndirectDrawBuffer,
+// which owns it and keeps it the same size
+pub struct ChunkInfoBuffer {
+    buffer: Buffer,
+}
+
+fn create_info_buffer(capacity: usize) -> Buffer {
+    create_buffer(&BufferDescription {
+        size: (capacity * std::mem::size_of::<ChunkInfo>()) as u64,
+        usage: BufferUsage::STORAGE | BufferUsage::TRANSFER_SRC | BufferUsage::TRANSFER_DST,
+        memory_type: MemoryType::DeviceLocal,
+    })
+}
+
+impl ChunkInfoBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ChunkInfoBuffer { buffer: create_info_buffer(capacity) }
+    }
+
+    pub fn write(&self, cmd: &mut CommandBuffer, slot: usize, coords: (i32, i32, i32)) {
+        cmd.update_buffer(&self.buffer, self.slot_offset(slot), &[ChunkInfo::from_coords(coords)]);
+    }
+
+    // copies the first count entries into a new buffer, returns the old one to be retired
+    pub fn grow(&mut self, cmd: &mut CommandBuffer, capacity: usize, count: usize) -> Buffer {
+        let buffer = create_info_buffer(capacity);
+        if count > 0 {
+            cmd.copy_buffer(
+                &self.buffer,
+                &buffer,
+                &[BufferCopy {
+                    src_offset: 0,
+                    dst_offset: 0,
+                    size: self.slot_offset(count),
+                }],
+            );
+        }
+        std::mem::replace(&mut self.buffer, buffer)
+    }
+
+    fn slot_offset(&self, slot: usize) -> u64 {
+        (slot * std::mem::size_of::<ChunkInfo>()) as u64
+    }
+
+    pub fn raw(&self) -> Buffer {
+        self.buffer
+    }
+}
+
+impl Drop for ChunkInfoBuffer {
+    fn drop(&mut self) {
+        destroy_buffer(self.buffer);
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::BTreeMap;
+
+use super::BufferLocation;
+
+// two level segregated fit (tlsf). free blocks are kept in lists by size class:
+// the first level is the power of two below the size, the second splits that range
+// into SL_COUNT equal parts. a pair of bitmaps finds a non empty list big enough in O(1).
+// only cpu side bookkeeping, the memory itself lives in the gpu buffer
+const SL_BITS: u32 = 4;
+const SL_COUNT: usize = 1 << SL_BITS;
+// sizes below SL_COUNT all go in the first level, one list per size
+const FL_COUNT: usize = 64 - SL_BITS as usize + 1;
+
+const NONE: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug)]
+pub struct AllocatorStats {
+    pub capacity: u64,
+    pub used_bytes: u64,
+    pub largest_free_block: u64,
+    // 0 when all the free space is one block, close to 1 when it is split into many small ones
+    pub fragmentation: f64,
+    pub allocations: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    offset: u64,
+    size: u64,
+    free: bool,
+    // neighbours in the buffer, for merging freed blocks
+    prev_phys: usize,
+    next_phys: usize,
+    // neighbours in the free list of the block's size class
+    prev_free: usize,
+    next_free: usize,
+}
+
+pub struct FaceAllocator {
+    nodes: Vec<Node>,
+    // indices of unused entries in nodes
+    spare_nodes: Vec<usize>,
+    heads: [[usize; SL_COUNT]; FL_COUNT],
+    fl_bitmap: u64,
+    sl_bitmaps: [u32; FL_COUNT],
+    // allocated and free blocks by offset, ordered so compaction can find its way down the buffer
+    used: BTreeMap<u64, usize>,
+    free_offsets: BTreeMap<u64, usize>,
+    // the block at the end of the buffer
+    last: usize,
+    capacity: u64,
+    used_bytes: u64,
+}
+
+// the size class a block of this size is filed under
+fn mapping(size: u64) -> (usize, usize) {
+    let fl = 63 - size.leading_zeros();
+    if fl < SL_BITS {
+        return (0, size as usize);
+    }
+    let sl = (size >> (fl - SL_BITS)) as usize & (SL_COUNT - 1);
+    return ((fl - SL_BITS + 1) as usize, sl);
+}
+
+// the smallest class whose blocks all fit size, past the last class if no class is certain to
+fn search_mapping(size: u64) -> (usize, usize) {
+    let fl = 63 - size.leading_zeros();
+    if fl < SL_BITS {
+        return mapping(size);
+    }
+    let Some(rounded) = size.checked_add((1 << (fl - SL_BITS)) - 1) else {
+        return (FL_COUNT, 0);
+    };
+    return mapping(rounded);
+}
+
+impl FaceAllocator {
+    pub fn new(capacity: u64) -> FaceAllocator {
+        let mut allocator = FaceAllocator {
+            nodes: Vec::new(),
+            spare_nodes: Vec::new(),
+            heads: [[NONE; SL_COUNT]; FL_COUNT],
+            fl_bitmap: 0,
+            sl_bitmaps: [0; FL_COUNT],
+            used: BTreeMap::new(),
+            free_offsets: BTreeMap::new(),
+            last: NONE,
+            capacity: 0,
+            used_bytes: 0,
+        };
+        allocator.grow(capacity);
+        return allocator;
+    }
+
+    pub fn capacity(&self) -> u64 {
+        return self.capacity;
+    }
+
+    pub fn allocate(&mut self, size: u64) -> Option<BufferLocation> {
+        if size == 0 {
+            return None;
+        }
+        let index = self.find_free(size)?;
+        return Some(self.take_free(index, size));
+    }
+
+    // the free block with the lowest offset that fits size and ends at or before end.
+    // slower than allocate, it goes through the free blocks in order, but keeps the used space packed at the start
+    pub fn allocate_lowest(&mut self, size: u64, end: u64) -> Option<BufferLocation> {
+        if size == 0 || self.find_free(size).is_none() {
+            return None;
+        }
+        let index = self
+            .free_offsets
+            .range(..end)
+            .map(|(_, &index)| index)
+            .take_while(|&index| self.nodes[index].offset + size <= end)
+            .find(|&index| self.nodes[index].size >= size)?;
+        return Some(self.take_free(index, size));
+    }
+
+    // allocates the start of a free block
+    fn take_free(&mut self, index: usize, size: u64) -> BufferLocation {
+        self.remove_free(index);
+
+        // the rest of the block goes back to the free lists
+        let remaining = self.nodes[index].size - size;
+        if remaining > 0 {
+            let rest = self.new_node(Node {
+                offset: self.nodes[index].offset + size,
+                size: remaining,
+                free: true,
+                prev_phys: index,
+                next_phys: self.nodes[index].next_phys,
+                prev_free: NONE,
+                next_free: NONE,
+            });
+            self.link_after(index, rest);
+            self.nodes[index].size = size;
+            self.insert_free(rest);
+        }
+
+        let node = &mut self.nodes[index];
+        node.free = false;
+        self.used.insert(node.offset, index);
+        self.used_bytes += size;
+        return BufferLocation { offset: node.offset, size };
+    }
+
+    pub fn free(&mut self, loc: BufferLocation) {
+        let Some(mut index) = self.used.remove(&loc.offset) else {
+            panic!("FaceAllocator: freeing {} which is not allocated", loc.offset);
+        };
+        self.used_bytes -= self.nodes[index].size;
+        self.nodes[index].free = true;
+
+        let prev = self.nodes[index].prev_phys;
+        if prev != NONE && self.nodes[prev].free {
+            self.remove_free(prev);
+            self.merge_into_prev(index);
+            index = prev;
+        }
+        let next = self.nodes[index].next_phys;
+        if next != NONE && self.nodes[next].free {
+            self.remove_free(next);
+            self.merge_into_prev(next);
+        }
+        self.insert_free(index);
+    }
+
+    // adds free space at the end of the buffer
+    pub fn grow(&mut self, new_capacity: u64) {
+        assert!(new_capacity >= self.capacity, "FaceAllocator: can't shrink");
+        let added = new_capacity - self.capacity;
+        if added == 0 {
+            return;
+        }
+
+        if self.last != NONE && self.nodes[self.last].free {
+            let last = self.last;
+            self.remove_free(last);
+            self.nodes[last].size += added;
+            self.insert_free(last);
+        } else {
+            let node = self.new_node(Node {
+                offset: self.capacity,
+                size: added,
+                free: true,
+                prev_phys: self.last,
+                next_phys: NONE,
+                prev_free: NONE,
+                next_free: NONE,
+            });
+            if self.last != NONE {
+                self.nodes[self.last].next_phys = node;
+            }
+            self.last = node;
+            self.insert_free(node);
+        }
+        self.capacity = new_capacity;
+    }
+
+    // size of the free block at the end of the buffer, growing adds to it
+    pub fn free_tail(&self) -> u64 {
+        if self.last != NONE && self.nodes[self.last].free {
+            return self.nodes[self.last].size;
+        }
+        return 0;
+    }
+
+    // the allocated block furthest into the buffer that starts before offset, u64::MAX for the last one
+    pub fn prev_allocation(&self, offset: u64) -> Option<BufferLocation> {
+        return self.used.range(..offset).next_back().map(|(_, &index)| BufferLocation {
+            offset: self.nodes[index].offset,
+            size: self.nodes[index].size,
+        });
+    }
+
+    // where the lowest free block starts, everything before it is in use
+    pub fn first_free_offset(&self) -> Option<u64> {
+        return self.free_offsets.keys().next().copied();
+    }
+
+    // every allocated block, in no particular order
+    pub fn allocations(&self) -> impl Iterator<Item = BufferLocation> + '_ {
+        return self.used.values().map(|&i| BufferLocation {
+            offset: self.nodes[i].offset,
+            size: self.nodes[i].size,
+        });
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let free_bytes = self.capacity - self.used_bytes;
+        let largest_free_block = self.largest_free();
+        let fragmentation = if free_bytes == 0 { 0.0 } else { 1.0 - largest_free_block as f64 / free_bytes as f64 };
+        return AllocatorStats {
+            capacity: self.capacity,
+            used_bytes: self.used_bytes,
+            largest_free_block,
+            fragmentation,
+            allocations: self.used.len(),
+        };
+    }
+
+    // the biggest block is in the highest non empty class, that list is the only one to scan
+    fn largest_free(&self) -> u64 {
+        if self.fl_bitmap == 0 {
+            return 0;
+        }
+        let fl = 63 - self.fl_bitmap.leading_zeros() as usize;
+        let sl = 31 - self.sl_bitmaps[fl].leading_zeros() as usize;
+        let mut largest = 0;
+        let mut index = self.heads[fl][sl];
+        while index != NONE {
+            largest = largest.max(self.nodes[index].size);
+            index = self.nodes[index].next_free;
+        }
+        return largest;
+    }
+
+    fn find_free(&self, size: u64) -> Option<usize> {
+        let (fl, sl) = search_mapping(size);
+        if fl < FL_COUNT
+            && let Some(index) = self.first_free_from(fl, sl)
+        {
+            return Some(index);
+        }
+
+        // nothing is certain to fit, but a block in the size's own class still might
+        let (fl, sl) = mapping(size);
+        let mut index = self.heads[fl][sl];
+        while index != NONE {
+            if self.nodes[index].size >= size {
+                return Some(index);
+            }
+            index = self.nodes[index].next_free;
+        }
+        return None;
+    }
+
+    // the head of the first non empty list at or above the class
+    fn first_free_from(&self, fl: usize, sl: usize) -> Option<usize> {
+        let sl_map = self.sl_bitmaps[fl] & (u32::MAX << sl);
+        if sl_map != 0 {
+            return Some(self.heads[fl][sl_map.trailing_zeros() as usize]);
+        }
+        let fl_map = self.fl_bitmap & u64::MAX.checked_shl(fl as u32 + 1).unwrap_or(0);
+        if fl_map == 0 {
+            return None;
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        let sl = self.sl_bitmaps[fl].trailing_zeros() as usize;
+        return Some(self.heads[fl][sl]);
+    }
+
+    fn new_node(&mut self, node: Node) -> usize {
+        if let Some(index) = self.spare_nodes.pop() {
+            self.nodes[index] = node;
+            return index;
+        }
+        self.nodes.push(node);
+        return self.nodes.len() - 1;
+    }
+
+    // puts node, whose prev_phys is already index, right after index in the buffer
+    fn link_after(&mut self, index: usize, node: usize) {
+        let next = self.nodes[index].next_phys;
+        self.nodes[index].next_phys = node;
+        if next != NONE {
+            self.nodes[next].prev_phys = node;
+        }
+        if self.last == index {
+            self.last = node;
+        }
+    }
+
+    // folds a block into the one before it in the buffer
+    fn merge_into_prev(&mut self, index: usize) {
+        let Node { size, prev_phys: prev, next_phys: next, .. } = self.nodes[index];
+        self.nodes[prev].size += size;
+        self.nodes[prev].next_phys = next;
+        if next != NONE {
+            self.nodes[next].prev_phys = prev;
+        }
+        if self.last == index {
+            self.last = prev;
+        }
+        self.spare_nodes.push(index);
+    }
+
+    fn insert_free(&mut self, index: usize) {
+        let (fl, sl) = mapping(self.nodes[index].size);
+        let head = self.heads[fl][sl];
+        self.nodes[index].prev_free = NONE;
+        self.nodes[index].next_free = head;
+        if head != NONE {
+            self.nodes[head].prev_free = index;
+        }
+        self.heads[fl][sl] = index;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmaps[fl] |= 1 << sl;
+        self.free_offsets.insert(self.nodes[index].offset, index);
+    }
+
+    fn remove_free(&mut self, index: usize) {
+        self.free_offsets.remove(&self.nodes[index].offset);
+        let Node { size, prev_free: prev, next_free: next, .. } = self.nodes[index];
+        if prev != NONE {
+            self.nodes[prev].next_free = next;
+        }
+        if next != NONE {
+            self.nodes[next].prev_free = prev;
+        }
+
+        let (fl, sl) = mapping(size);
+        if self.heads[fl][sl] == index {
+            self.heads[fl][sl] = next;
+            if next == NONE {
+                self.sl_bitmaps[fl] &= !(1 << sl);
+                if self.sl_bitmaps[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rng;
+
+    // the smallest size filed under the class
+    fn class_start(fl: usize, sl: usize) -> u64 {
+        if fl == 0 {
+            return sl as u64;
+        }
+        let base = 1u64 << (fl as u32 - 1 + SL_BITS);
+        return base + sl as u64 * (base >> SL_BITS);
+    }
+
+    // walks the blocks in buffer order and checks them against the free lists, the used map and the stats
+    fn check(allocator: &FaceAllocator, live: &[BufferLocation]) {
+        let mut first = allocator.last;
+        while allocator.nodes[first].prev_phys != NONE {
+            first = allocator.nodes[first].prev_phys;
+        }
+
+        let (mut offset, mut free_bytes, mut free_blocks, mut largest) = (0, 0, 0, 0);
+        let mut previous_free = false;
+        let mut index = first;
+        let mut previous = NONE;
+        while index != NONE {
+            let node = allocator.nodes[index];
+            assert_eq!(node.offset, offset, "blocks have to cover the buffer without gaps or overlaps");
+            assert_eq!(node.prev_phys, previous);
+            assert!(node.size > 0);
+            if node.free {
+                assert!(!previous_free, "free block at {} wasn't merged with the one before it", node.offset);
+                assert_eq!(allocator.free_offsets.get(&node.offset), Some(&index));
+                free_bytes += node.size;
+                free_blocks += 1;
+                largest = largest.max(node.size);
+            } else {
+                assert_eq!(allocator.used.get(&node.offset), Some(&index));
+            }
+            previous_free = node.free;
+            offset += node.size;
+            previous = index;
+            index = node.next_phys;
+        }
+        assert_eq!(previous, allocator.last);
+        assert_eq!(offset, allocator.capacity);
+
+        // every free block is in the list of its class, and the bitmaps say which lists aren't empty
+        let mut listed = 0;
+        for fl in 0..FL_COUNT {
+            for sl in 0..SL_COUNT {
+                let mut index = allocator.heads[fl][sl];
+                assert_eq!(index != NONE, allocator.sl_bitmaps[fl] & (1 << sl) != 0);
+                let mut prev = NONE;
+                while index != NONE {
+                    let node = allocator.nodes[index];
+                    assert!(node.free);
+                    assert_eq!(mapping(node.size), (fl, sl));
+                    assert_eq!(node.prev_free, prev);
+                    listed += 1;
+                    prev = index;
+                    index = node.next_free;
+                }
+            }
+            assert_eq!(allocator.sl_bitmaps[fl] != 0, allocator.fl_bitmap & (1 << fl) != 0);
+        }
+        assert_eq!(listed, free_blocks);
+        assert_eq!(allocator.free_offsets.len(), free_blocks);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.capacity, allocator.capacity);
+        assert_eq!(stats.used_bytes + free_bytes, stats.capacity);
+        assert_eq!(stats.used_bytes, live.iter().map(|l| l.size).sum::<u64>());
+        assert_eq!(stats.allocations, live.len());
+        assert_eq!(stats.largest_free_block, largest);
+        assert!((0.0..=1.0).contains(&stats.fragmentation));
+
+        let mut expected = live.to_vec();
+        let mut actual: Vec<BufferLocation> = allocator.allocations().collect();
+        expected.sort_unstable_by_key(|l| l.offset);
+        actual.sort_unstable_by_key(|l| l.offset);
+        assert!(expected.iter().zip(&actual).all(|(e, a)| e.offset == a.offset && e.size == a.size));
+        assert_eq!(expected.len(), actual.len());
+    }
+
+    #[test]
+    fn classes_cover_their_sizes() {
+        for (size, class, search) in [
+            (15, (0, 15), (0, 15)),
+            (16, (1, 0), (1, 0)),
+            (17, (1, 1), (1, 1)),
+            (31, (1, 15), (1, 15)),
+            (32, (2, 0), (2, 0)),
+            (33, (2, 0), (2, 1)),
+            (u64::MAX, (FL_COUNT - 1, SL_COUNT - 1), (FL_COUNT, 0)),
+        ] {
+            assert_eq!(mapping(size), class, "mapping({size})");
+            assert_eq!(search_mapping(size), search, "search_mapping({size})");
+        }
+
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let sizes = (1..5000).chain((0..5000).map(|_| rng.next() >> rng.below(64)).filter(|&s| s > 0));
+        for size in sizes {
+            let (fl, sl) = mapping(size);
+            assert!(class_start(fl, sl) <= size, "{size} is below its class");
+            let (fl, sl) = search_mapping(size);
+            // anything in the searched class is big enough
+            assert!(fl == FL_COUNT || class_start(fl, sl) >= size, "class of {size} holds smaller blocks");
+            assert!((fl, sl) >= mapping(size));
+        }
+    }
+
+    #[test]
+    fn random_allocations_never_overlap() {
+        let mut rng = Rng(0x1234_5678_9abc_def0);
+        let mut allocator = FaceAllocator::new(1 << 16);
+        let mut live: Vec<BufferLocation> = Vec::new();
+
+        for step in 0..20000 {
+            match rng.below(10) {
+                0..5 => {
+                    // mostly mesh sized blocks, some big ones
+                    let size = if rng.below(20) == 0 { 1 + rng.below(1 << 14) } else { 1 + rng.below(600) };
+                    match allocator.allocate(size) {
+                        Some(loc) => {
+                            assert_eq!(loc.size, size);
+                            live.push(loc);
+                        }
+                        None => assert!(allocator.stats().largest_free_block < size, "{size} bytes fit but weren't found"),
+                    }
+                }
+                5..9 if !live.is_empty() => {
+                    let loc = live.swap_remove(rng.below(live.len() as u64) as usize);
+                    allocator.free(loc);
+                }
+                9 if rng.below(20) == 0 => allocator.grow(allocator.capacity() + 1 + rng.below(1 << 14)),
+                _ => {}
+            }
+
+            if step % 50 == 0 {
+                check(&allocator, &live);
+            }
+            let last = allocator.prev_allocation(u64::MAX);
+            assert_eq!(last.map(|l| l.offset), live.iter().map(|l| l.offset).max());
+        }
+
+        for loc in live.drain(..) {
+            allocator.free(loc);
+        }
+        check(&allocator, &live);
+        assert_eq!(allocator.stats().largest_free_block, allocator.capacity());
+        assert_eq!(allocator.free_tail(), allocator.capacity());
+    }
+
+    #[test]
+    fn growing_extends_the_free_tail() {
+        let mut allocator = FaceAllocator::new(100);
+        let a = allocator.allocate(60).unwrap();
+        assert_eq!(allocator.free_tail(), 40);
+        allocator.grow(200);
+        assert_eq!(allocator.free_tail(), 140);
+
+        // a used block at the end gets a new free block after it
+        let b = allocator.allocate(140).unwrap();
+        assert_eq!(allocator.free_tail(), 0);
+        allocator.grow(250);
+        assert_eq!(allocator.free_tail(), 50);
+        assert!(allocator.allocate(51).is_none());
+        assert!(allocator.allocate(u64::MAX).is_none());
+        check(&allocator, &[a, b]);
+    }
+
+    #[test]
+    fn lowest_allocations_fill_the_first_hole_that_fits() {
+        let mut allocator = FaceAllocator::new(1000);
+        let blocks: Vec<BufferLocation> = (0..10).map(|_| allocator.allocate(100).unwrap()).collect();
+        // holes of 100 at 100, 200 at 400 and 100 at 800
+        for i in [1, 4, 5, 8] {
+            allocator.free(blocks[i]);
+        }
+        assert_eq!(allocator.first_free_offset(), Some(100));
+
+        assert_eq!(allocator.allocate_lowest(150, 900).map(|l| l.offset), Some(400));
+        assert_eq!(allocator.allocate_lowest(50, 900).map(|l| l.offset), Some(100));
+        assert_eq!(allocator.allocate_lowest(60, 900).map(|l| l.offset), Some(800));
+        // what's left is 50 at 150, 50 at 550 and 40 at 860, the end bound rules out the last one
+        assert_eq!(allocator.allocate_lowest(40, 880).map(|l| l.offset), Some(150));
+        assert_eq!(allocator.allocate_lowest(45, 550).map(|l| l.offset), None);
+        assert_eq!(allocator.allocate_lowest(45, 600).map(|l| l.offset), Some(550));
+        assert!(allocator.allocate_lowest(60, 1000).is_none());
+
+        let live: Vec<BufferLocation> = allocator.allocations().collect();
+        check(&allocator, &live);
+    }
+
+    #[test]
+    fn prev_allocation_walks_down_past_free_blocks() {
+        let mut allocator = FaceAllocator::new(1000);
+        let blocks: Vec<BufferLocation> = (0..6).map(|i| allocator.allocate(50 + i * 10).unwrap()).collect();
+        allocator.free(blocks[2]);
+        allocator.free(blocks[4]);
+
+        let mut offsets = Vec::new();
+        let mut cursor = allocator.prev_allocation(u64::MAX);
+        while let Some(loc) = cursor {
+            offsets.push(loc.offset);
+            cursor = allocator.prev_allocation(loc.offset);
+        }
+        assert_eq!(offsets, [5, 3, 1, 0].map(|i| blocks[i].offset));
+
+        // offsets that aren't allocated work too, e.g. one that was freed after the walk got there
+        assert_eq!(allocator.prev_allocation(blocks[4].offset + 1).map(|l| l.offset), Some(blocks[3].offset));
+        assert!(allocator.prev_allocation(0).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated")]
+    fn freeing_an_unknown_offset_panics() {
+        let mut allocator = FaceAllocator::new(100);
+        allocator.allocate(10).unwrap();
+        allocator.free(BufferLocation { offset: 5, size: 10 });
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated")]
+    fn freeing_twice_panics() {
+        let mut allocator = FaceAllocator::new(100);
+        let loc = allocator.allocate(10).unwrap();
+        allocator.free(loc);
+        allocator.free(loc);
+    }
+}
@@ -0,0 +1,116 @@
+use glam::{Mat4, Vec3, Vec4};
+
+// CPU version of the plane test in cull.slang
+#[derive(Clone, Copy, PartialEq)]
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+fn normalize_plane(row: Vec4) -> Vec4 {
+    let len = row.truncate().length();
+    if len < 1e-8 {
+        return Vec4::ZERO;
+    }
+    return row / len;
+}
+
+impl Frustum {
+    pub fn from_view_proj(view_proj: &Mat4) -> Frustum {
+        let row0 = view_proj.row(0);
+        let row1 = view_proj.row(1);
+        let row2 = view_proj.row(2);
+        let row3 = view_proj.row(3);
+
+        return Frustum {
+            planes: [
+                normalize_plane(row3 + row0),
+                normalize_plane(row3 - row0),
+                normalize_plane(row3 + row1),
+                normalize_plane(row3 - row1),
+                normalize_plane(row2),
+                normalize_plane(row3 - row2),
+            ],
+        };
+    }
+
+    pub fn is_aabb_visible(&self, min: Vec3, max: Vec3) -> bool {
+        let center = (min + max) * 0.5;
+        let half_size = (max - min) * 0.5;
+
+        for plane in &self.planes {
+            let n = plane.truncate();
+            let radius = n.abs().dot(half_size);
+            let dist = n.dot(center) + plane.w;
+            if dist + radius < 0.0 {
+                return false;
+            }
+        }
+
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec3;
+
+    // looking down -z from the origin, with the same y flip as the camera
+    fn test_frustum() -> Frustum {
+        let mut proj = Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 100.0);
+        proj.y_axis.y *= -1.0;
+        let view = Mat4::look_at_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y);
+        return Frustum::from_view_proj(&(proj * view));
+    }
+
+    #[test]
+    fn planes_are_normalized_and_face_inwards() {
+        let frustum = test_frustum();
+        let inside = vec3(0.0, 0.0, -10.0);
+        for plane in frustum.planes {
+            assert!((plane.truncate().length() - 1.0).abs() < 1e-5);
+            assert!(plane.truncate().dot(inside) + plane.w > 0.0);
+        }
+
+        // with a 90 degree fov the side planes are at 45 degrees
+        let left = frustum.planes[0];
+        assert!((left.truncate() - vec3(1.0, 0.0, -1.0).normalize()).length() < 1e-5);
+        assert!(left.w.abs() < 1e-5);
+    }
+
+    #[test]
+    fn boxes_outside_any_plane_are_culled() {
+        let frustum = test_frustum();
+        let visible = |center: Vec3| frustum.is_aabb_visible(center - 1.0, center + 1.0);
+
+        assert!(visible(vec3(0.0, 0.0, -10.0)));
+        // behind the camera
+        assert!(!visible(vec3(0.0, 0.0, 10.0)));
+        // left, right, below and above
+        assert!(!visible(vec3(-20.0, 0.0, -10.0)));
+        assert!(!visible(vec3(20.0, 0.0, -10.0)));
+        assert!(!visible(vec3(0.0, -20.0, -10.0)));
+        assert!(!visible(vec3(0.0, 20.0, -10.0)));
+        // past the far plane
+        assert!(!visible(vec3(0.0, 0.0, -110.0)));
+    }
+
+    #[test]
+    fn boxes_crossing_a_plane_stay_visible() {
+        let frustum = test_frustum();
+        // the camera is inside this one
+        assert!(frustum.is_aabb_visible(vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0)));
+        // only a corner pokes into the view
+        assert!(frustum.is_aabb_visible(vec3(-12.0, -1.0, -11.0), vec3(-10.5, 1.0, -9.0)));
+        assert!(!frustum.is_aabb_visible(vec3(-13.0, -1.0, -11.0), vec3(-11.5, 1.0, -9.0)));
+        // straddling the far plane
+        assert!(frustum.is_aabb_visible(vec3(-1.0, -1.0, -101.0), vec3(1.0, 1.0, -99.0)));
+    }
+
+    #[test]
+    fn degenerate_planes_accept_everything() {
+        let frustum = Frustum::from_view_proj(&Mat4::ZERO);
+        assert!(frustum.planes.iter().all(|p| *p == Vec4::ZERO));
+        assert!(frustum.is_aabb_visible(vec3(1e6, 1e6, 1e6), vec3(1e6 + 1.0, 1e6 + 1.0, 1e6 + 1.0)));
+    }
+}
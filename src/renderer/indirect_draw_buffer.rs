@@ -1,5 +1,8 @@
 use sgpu::*;
 
+use super::ChunkInfoBuffer;
+use super::retired_buffers::RetiredBuffers;
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct IndirectDrawCommand {
@@ -11,67 +14,139 @@ pub struct IndirectDrawCommand {
     pub _pad: f32,
 }
 
-impl IndirectDrawCommand {
-    pub fn zeroed() -> Self {
-        IndirectDrawCommand {
-            vertex_count: 0,
-            instance_count: 0,
-            first_vertex: 0,
-            first_instance: 0,
-            world_pos: [0; 3],
-            _pad: 0.0,
-        }
-    }
-}
-
+// the draw commands of every chunk with a mesh, packed into the first count() slots.
+// removing a command moves the last one into its slot, so the chunk that owned the last
+// slot has to be told its new one. doubles in size when full
 pub struct IndirectDrawBuffer {
     buffer: Buffer,
-    max_commands: usize,
-    count: usize,
-    free_indices: Vec<usize>,
+    chunk_info: ChunkInfoBuffer,
+    capacity: usize,
+    // cpu copies of the live commands and the chunk each one draws, indexed by slot
+    commands: Vec<IndirectDrawCommand>,
+    owners: Vec<(i32, i32, i32)>,
+    retired: RetiredBuffers,
+}
+
+fn create_command_buffer(capacity: usize) -> Buffer {
+    create_buffer(&BufferDescription {
+        size: (capacity * std::mem::size_of::<IndirectDrawCommand>()) as u64,
+        usage: BufferUsage::STORAGE | BufferUsage::INDIRECT | BufferUsage::TRANSFER_SRC | BufferUsage::TRANSFER_DST,
+        memory_type: MemoryType::DeviceLocal,
+    })
 }
 
 impl IndirectDrawBuffer {
-    pub fn new(max_commands: usize) -> Self {
-        let capacity = max_commands * std::mem::size_of::<IndirectDrawCommand>();
+    pub fn new(initial_commands: usize) -> Self {
+        let capacity = initial_commands.max(1);
         IndirectDrawBuffer {
-            buffer: create_buffer(&BufferDescription {
-                size: capacity as u64,
-                usage: BufferUsage::STORAGE | BufferUsage::INDIRECT | BufferUsage::TRANSFER_DST,
-                memory_type: MemoryType::DeviceLocal,
-            }),
-            max_commands,
-            count: 0,
-            free_indices: Vec::new(),
+            buffer: create_command_buffer(capacity),
+            chunk_info: ChunkInfoBuffer::new(capacity),
+            capacity,
+            commands: Vec::with_capacity(capacity),
+            owners: Vec::with_capacity(capacity),
+            retired: RetiredBuffers::new(),
         }
     }
 
-    pub fn allocate_slot(&mut self) -> usize {
-        if let Some(idx) = self.free_indices.pop() {
-            return idx;
+    // adds a command at the end, returns its slot
+    pub fn push(&mut self, cmd: &mut CommandBuffer, coords: (i32, i32, i32), draw: IndirectDrawCommand) -> usize {
+        if self.commands.len() == self.capacity {
+            self.grow(cmd);
         }
-        let idx = self.count;
-        self.count += 1;
-        if idx >= self.max_commands {
-            panic!("IndirectDrawBuffer: out of pre-allocated slots");
+        let slot = self.commands.len();
+        self.commands.push(draw);
+        self.owners.push(coords);
+        cmd.update_buffer(&self.buffer, self.slot_offset(slot), &[draw]);
+        self.chunk_info.write(cmd, slot, coords);
+        slot
+    }
+
+    pub fn set(&mut self, cmd: &mut CommandBuffer, slot: usize, draw: IndirectDrawCommand) {
+        self.commands[slot] = draw;
+        cmd.update_buffer(&self.buffer, self.slot_offset(slot), &[draw]);
+    }
+
+    // for meshes that were moved inside the face buffer
+    pub fn set_first_vertex(&mut self, cmd: &mut CommandBuffer, slot: usize, first_vertex: u32) {
+        self.commands[slot].first_vertex = first_vertex;
+        let field = std::mem::offset_of!(IndirectDrawCommand, first_vertex) as u64;
+        cmd.update_buffer(&self.buffer, self.slot_offset(slot) + field, &[first_vertex]);
+    }
+
+    // removes the command in slot by moving the last one into it.
+    // returns the chunk whose command moved and its new slot.
+    // when removing several, go from the highest slot down so no moved command is one still to be removed
+    pub fn swap_remove(&mut self, cmd: &mut CommandBuffer, slot: usize) -> Option<((i32, i32, i32), usize)> {
+        self.commands.swap_remove(slot);
+        self.owners.swap_remove(slot);
+        if slot == self.commands.len() {
+            return None;
         }
-        idx
+
+        let coords = self.owners[slot];
+        cmd.update_buffer(&self.buffer, self.slot_offset(slot), &[self.commands[slot]]);
+        self.chunk_info.write(cmd, slot, coords);
+        Some((coords, slot))
     }
 
-    pub fn free_slot(&mut self, slot: usize) {
-        self.free_indices.push(slot);
+    fn grow(&mut self, cmd: &mut CommandBuffer) {
+        let capacity = self.capacity * 2;
+        let count = self.commands.len();
+        let buffer = create_command_buffer(capacity);
+
+        // commands already written into cmd have to land before they are copied
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::TransferWrite],
+            next_accesses: &[AccessType::TransferRead],
+        });
+        cmd.copy_buffer(
+            &self.buffer,
+            &buffer,
+            &[BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size: self.slot_offset(count),
+            }],
+        );
+        let old_info = self.chunk_info.grow(cmd, capacity, count);
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::TransferWrite],
+            next_accesses: &[AccessType::TransferWrite],
+        });
+
+        self.retired.retire(std::mem::replace(&mut self.buffer, buffer));
+        self.retired.retire(old_info);
+        self.capacity = capacity;
     }
 
-    pub fn slot_offset(&self, slot: usize) -> u64 {
+    fn slot_offset(&self, slot: usize) -> u64 {
         (slot * std::mem::size_of::<IndirectDrawCommand>()) as u64
     }
 
+    // old buffers can be destroyed once this submission, which comes after every use of them, is done
+    pub fn retire_after(&mut self, counter: Counter) {
+        self.retired.retire_after(counter);
+    }
+
+    pub fn destroy_retired(&mut self) {
+        self.retired.destroy_finished();
+    }
+
     pub fn raw(&self) -> Buffer {
         self.buffer
     }
 
+    pub fn chunk_info(&self) -> &ChunkInfoBuffer {
+        &self.chunk_info
+    }
+
+    // exactly the number of chunks with a mesh
     pub fn count(&self) -> usize {
-        self.count
+        self.commands.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
     pub fn stride(&self) -> u32 {
@@ -1,8 +1,20 @@
+mod block_palette;
+mod chunk_info_buffer;
+mod face_allocator;
+mod frustum;
 mod indirect_draw_buffer;
+mod retired_buffers;
+mod staging_buffer;
 mod vertex_buffer;
 
+use crate::chunk::BlockRegistry;
+use block_palette::BlockPalette;
+use chunk_info_buffer::ChunkInfoBuffer;
+pub use frustum::Frustum;
 pub use indirect_draw_buffer::{IndirectDrawBuffer, IndirectDrawCommand};
+use retired_buffers::RetiredBuffers;
 use sgpu::*;
+pub use staging_buffer::StagingBuffer;
 pub use vertex_buffer::FaceBuffer;
 use winit::dpi::PhysicalSize;
 
@@ -14,23 +26,46 @@ pub struct BufferLocation {
 
 const VERTEX_SHADER: &[u8] = include_bytes!("../../shaders/compiled/vert.spv");
 const FRAGMENT_SHADER: &[u8] = include_bytes!("../../shaders/compiled/frag.spv");
+const CULL_SHADER: &[u8] = include_bytes!("../../shaders/compiled/cull.spv");
+
+const CULL_GROUP_SIZE: u32 = 64;
 
 pub struct Renderer {
     pipeline: RasterizationPipeline,
+    cull_pipeline: ComputePipeline,
     depth_image: Image,
+    block_palette: BlockPalette,
+    // visible draw commands written by the culling pass, and how many there are
+    culled_buffer: Buffer,
+    count_buffer: Buffer,
+    // how many commands the culled buffer holds, follows the IndirectDrawBuffer's capacity
+    max_commands: usize,
+    retired: RetiredBuffers,
     size: PhysicalSize<u32>,
 }
 
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct CullPushData {
+    view_proj: [f32; 16],
+    chunk_info_id: u32,
+    source_id: u32,
+    culled_id: u32,
+    count_id: u32,
+    chunk_count: u32,
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 struct PushData {
     view_proj: [f32; 16],
     face_buffer_id: u32,
     indirecr_draw_buffer_id: u32,
+    block_palette_id: u32,
 }
 
 impl Renderer {
-    pub fn new(size: PhysicalSize<u32>) -> Renderer {
+    pub fn new(size: PhysicalSize<u32>, registry: &BlockRegistry, max_commands: usize) -> Renderer {
         let depth_image = create_image(&ImageDescription {
             usage: ImageUsage::DEPTH_STENCIL_ATTACHMENT,
             format: Format::D32Float,
@@ -72,7 +107,47 @@ impl Renderer {
             },
         });
 
-        return Renderer { pipeline, depth_image, size };
+        let cull_pipeline = create_compute_pipeline(&ComputePipelineDescription { shader: CULL_SHADER });
+
+        let block_palette = BlockPalette::new(registry);
+
+        let culled_buffer = create_culled_buffer(max_commands);
+        let count_buffer = create_buffer(&BufferDescription {
+            size: std::mem::size_of::<u32>() as u64,
+            usage: BufferUsage::STORAGE | BufferUsage::INDIRECT | BufferUsage::TRANSFER_DST,
+            memory_type: MemoryType::DeviceLocal,
+        });
+
+        return Renderer {
+            pipeline,
+            cull_pipeline,
+            depth_image,
+            block_palette,
+            culled_buffer,
+            count_buffer,
+            max_commands,
+            retired: RetiredBuffers::new(),
+            size,
+        };
+    }
+
+    // makes room for max_commands visible draw commands
+    pub fn reserve_commands(&mut self, max_commands: usize) {
+        if max_commands <= self.max_commands {
+            return;
+        }
+        // the culling pass rewrites it every frame, nothing to copy over
+        self.retired.retire(std::mem::replace(&mut self.culled_buffer, create_culled_buffer(max_commands)));
+        self.max_commands = max_commands;
+    }
+
+    // old culled buffers can be destroyed once this submission, which comes after every use of them, is done
+    pub fn retire_after(&mut self, counter: Counter) {
+        self.retired.retire_after(counter);
+    }
+
+    pub fn destroy_retired(&mut self) {
+        self.retired.destroy_finished();
     }
 
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
@@ -99,7 +174,41 @@ impl Renderer {
         self.size = size;
     }
 
-    pub fn render(&self, cmd: &mut CommandBuffer, swapchain_image: Image, face_buffer: &FaceBuffer, indirect_buffer: &IndirectDrawBuffer, view_proj: &glam::Mat4, chunk_count: u32) {
+    // compacts the draw commands of chunks inside the view frustum into the culled buffer
+    fn cull(&self, cmd: &mut CommandBuffer, indirect_buffer: &IndirectDrawBuffer, view_proj: &glam::Mat4, chunk_count: u32) {
+        // the previous frame can still be culling into or drawing from the same buffers
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::ComputeShaderStorageRead, AccessType::ComputeShaderStorageWrite, AccessType::VertexShaderStorageRead, AccessType::IndirectBuffer],
+            next_accesses: &[AccessType::TransferWrite],
+        });
+
+        cmd.update_buffer(&self.count_buffer, 0, &[0u32]);
+
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::TransferWrite],
+            next_accesses: &[AccessType::ComputeShaderStorageRead, AccessType::ComputeShaderStorageWrite],
+        });
+
+        cmd.bind_compute_pipeline(&self.cull_pipeline);
+        cmd.push_constants(&CullPushData {
+            view_proj: view_proj.to_cols_array(),
+            chunk_info_id: indirect_buffer.chunk_info().raw().descriptor_index(),
+            source_id: indirect_buffer.raw().descriptor_index(),
+            culled_id: self.culled_buffer.descriptor_index(),
+            count_id: self.count_buffer.descriptor_index(),
+            chunk_count,
+        });
+        cmd.dispatch(chunk_count.div_ceil(CULL_GROUP_SIZE), 1, 1);
+
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::ComputeShaderStorageWrite],
+            next_accesses: &[AccessType::VertexShaderStorageRead, AccessType::IndirectBuffer],
+        });
+    }
+
+    pub fn render(&self, cmd: &mut CommandBuffer, swapchain_image: Image, face_buffer: &FaceBuffer, indirect_buffer: &IndirectDrawBuffer, view_proj: &glam::Mat4) {
+        self.cull(cmd, indirect_buffer, view_proj, indirect_buffer.count() as u32);
+
         cmd.image_barrier(&ImageBarrier {
             view: swapchain_image.default_view(),
             previous_accesses: &[AccessType::Present],
@@ -149,10 +258,11 @@ impl Renderer {
                 recorder.push_constants(&PushData {
                     view_proj: view_proj.to_cols_array(),
                     face_buffer_id: face_buffer.raw().descriptor_index(),
-                    indirecr_draw_buffer_id: indirect_buffer.raw().descriptor_index(),
+                    indirecr_draw_buffer_id: self.culled_buffer.descriptor_index(),
+                    block_palette_id: self.block_palette.raw().descriptor_index(),
                 });
 
-                recorder.draw_indirect(&indirect_buffer.raw(), 0, chunk_count, indirect_buffer.stride());
+                recorder.draw_indirect_count(&self.culled_buffer, 0, &self.count_buffer, 0, self.max_commands as u32, indirect_buffer.stride());
             },
         );
 
@@ -165,8 +275,18 @@ impl Renderer {
     }
 }
 
+fn create_culled_buffer(max_commands: usize) -> Buffer {
+    return create_buffer(&BufferDescription {
+        size: (max_commands * std::mem::size_of::<IndirectDrawCommand>()) as u64,
+        usage: BufferUsage::STORAGE | BufferUsage::INDIRECT,
+        memory_type: MemoryType::DeviceLocal,
+    });
+}
+
 impl Drop for Renderer {
     fn drop(&mut self) {
         destroy_image(self.depth_image);
+        destroy_buffer(self.culled_buffer);
+        destroy_buffer(self.count_buffer);
     }
 }
@@ -0,0 +1,42 @@
+use sgpu::*;
+
+// buffers replaced by bigger ones. frames still in flight might read them,
+// so they are only destroyed once a submission recorded after the swap is done
+pub struct RetiredBuffers {
+    buffers: Vec<(Buffer, Option<Counter>)>,
+}
+
+impl RetiredBuffers {
+    pub fn new() -> RetiredBuffers {
+        return RetiredBuffers { buffers: Vec::new() };
+    }
+
+    pub fn retire(&mut self, buffer: Buffer) {
+        self.buffers.push((buffer, None));
+    }
+
+    // counter has to come from a submission that waits on every earlier use of the buffers
+    pub fn retire_after(&mut self, counter: Counter) {
+        for (_, retired_after) in self.buffers.iter_mut() {
+            retired_after.get_or_insert(counter);
+        }
+    }
+
+    pub fn destroy_finished(&mut self) {
+        self.buffers.retain(|(buffer, counter)| match counter {
+            Some(counter) if poll(*counter) => {
+                destroy_buffer(*buffer);
+                false
+            }
+            _ => true,
+        });
+    }
+}
+
+impl Drop for RetiredBuffers {
+    fn drop(&mut self) {
+        for (buffer, _) in self.buffers.drain(..) {
+            destroy_buffer(buffer);
+        }
+    }
+}
@@ -1,8 +1,20 @@
 use sgpu::*;
+use std::collections::VecDeque;
 
+// host visible memory that uploads are written into and copied to the gpu from.
+// used as a ring: writes go after the previous ones and wrap around to the start,
+// a submission's space is handed back once its counter signals
 pub struct StagingBuffer {
     buffer: Buffer,
     capacity: usize,
+    // where the next write goes and the start of the oldest write still in use
+    head: usize,
+    tail: usize,
+    used: usize,
+    // bytes written since the last submit, including the end skipped when wrapping
+    unsubmitted: usize,
+    // the end and size of every submitted batch and the counter it waits on, oldest first
+    in_flight: VecDeque<(usize, usize, Counter)>,
 }
 
 impl StagingBuffer {
@@ -14,17 +26,75 @@ impl StagingBuffer {
                 memory_type: MemoryType::PreferHost,
             }),
             capacity,
+            head: 0,
+            tail: 0,
+            used: 0,
+            unsubmitted: 0,
+            in_flight: VecDeque::new(),
         }
     }
 
-    pub fn write_at<T: Copy>(&self, data: &[T], byte_offset: u64) {
-        let bytes = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * std::mem::size_of::<T>())
+    // copies data in and returns its offset, None when the ring is too full until older uploads finish
+    pub fn write<T: Copy>(&mut self, data: &[T]) -> Option<u64> {
+        let size = std::mem::size_of_val(data);
+        assert!(size <= self.capacity, "StagingBuffer: {size} bytes don't fit in {}", self.capacity);
+        if self.used == 0 {
+            self.head = 0;
+            self.tail = 0;
+        }
+
+        // the free space is either the end and the start of the buffer or the gap between head and tail
+        let offset = if self.head > self.tail || self.used == 0 {
+            if self.capacity - self.head >= size {
+                self.head
+            } else if self.tail >= size {
+                let skipped = self.capacity - self.head;
+                self.used += skipped;
+                self.unsubmitted += skipped;
+                0
+            } else {
+                return None;
+            }
+        } else if self.tail - self.head >= size {
+            self.head
+        } else {
+            return None;
         };
+
+        self.write_at(data, offset as u64);
+        self.head = offset + size;
+        self.used += size;
+        self.unsubmitted += size;
+        Some(offset as u64)
+    }
+
+    fn write_at<T: Copy>(&self, data: &[T], byte_offset: u64) {
+        let bytes = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * std::mem::size_of::<T>()) };
         let dst = self.buffer.as_mut_slice::<u8>();
         dst[byte_offset as usize..byte_offset as usize + bytes.len()].copy_from_slice(bytes);
     }
 
+    // everything written since the last call is read by the submission behind counter
+    pub fn submitted(&mut self, counter: Counter) {
+        if self.unsubmitted == 0 {
+            return;
+        }
+        self.in_flight.push_back((self.head, self.unsubmitted, counter));
+        self.unsubmitted = 0;
+    }
+
+    // hands back the space of finished submissions
+    pub fn reclaim(&mut self) {
+        while let Some(&(end, size, counter)) = self.in_flight.front() {
+            if !sgpu::poll(counter) {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.tail = end;
+            self.used -= size;
+        }
+    }
+
     pub fn raw(&self) -> Buffer {
         self.buffer
     }
@@ -32,4 +102,15 @@ impl StagingBuffer {
     pub fn capacity(&self) -> usize {
         self.capacity
     }
+
+    // bytes waiting on a submission, or not submitted yet
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+impl Drop for StagingBuffer {
+    fn drop(&mut self) {
+        destroy_buffer(self.buffer);
+    }
 }
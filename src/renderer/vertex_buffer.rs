@@ -1,71 +1,145 @@
 use crate::chunk::Face;
 use sgpu::*;
+use std::fmt;
 
 use super::BufferLocation;
+use super::face_allocator::{AllocatorStats, FaceAllocator};
+use super::retired_buffers::RetiredBuffers;
 
-#[derive(Clone, Copy)]
-struct FreeBlock {
-    offset: u64,
-    size: u64,
+#[derive(Debug)]
+pub enum FaceBufferError {
+    // the buffer is already as big as it is allowed to get
+    OutOfMemory { requested: u64, max: u64 },
 }
 
+impl fmt::Display for FaceBufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaceBufferError::OutOfMemory { requested, max } => write!(f, "face buffer can't fit another {requested} bytes in {max} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for FaceBufferError {}
+
+// starts small and doubles, up to max_faces, when a mesh doesn't fit anywhere.
+// growing copies the live ranges into a new buffer, so raw() changes and has to be read again every frame
 pub struct FaceBuffer {
     buffer: Buffer,
-    free_blocks: Vec<FreeBlock>,
+    max_capacity: u64,
+    allocator: FaceAllocator,
+    retired: RetiredBuffers,
+}
+
+fn create_face_buffer(capacity: u64) -> Buffer {
+    return create_buffer(&BufferDescription {
+        size: capacity,
+        usage: BufferUsage::STORAGE | BufferUsage::TRANSFER_SRC | BufferUsage::TRANSFER_DST,
+        memory_type: MemoryType::DeviceLocal,
+    });
 }
 
 impl FaceBuffer {
-    pub fn new(max_faces: usize) -> FaceBuffer {
-        let capacity = (max_faces * std::mem::size_of::<Face>()) as u64;
+    pub fn new(initial_faces: usize, max_faces: usize) -> FaceBuffer {
+        let capacity = (initial_faces * std::mem::size_of::<Face>()) as u64;
         FaceBuffer {
-            buffer: create_buffer(&BufferDescription {
-                size: capacity,
-                usage: BufferUsage::STORAGE | BufferUsage::TRANSFER_DST,
-                memory_type: MemoryType::DeviceLocal,
-            }),
-            free_blocks: vec![FreeBlock { offset: 0, size: capacity }],
+            buffer: create_face_buffer(capacity),
+            max_capacity: (max_faces * std::mem::size_of::<Face>()) as u64,
+            allocator: FaceAllocator::new(capacity),
+            retired: RetiredBuffers::new(),
         }
     }
 
-    pub fn allocate(&mut self, num_faces: usize) -> BufferLocation {
+    // the copy into a bigger buffer, if one is needed, is recorded into cmd
+    pub fn allocate(&mut self, cmd: &mut CommandBuffer, num_faces: usize) -> Result<BufferLocation, FaceBufferError> {
         let size = (num_faces * std::mem::size_of::<Face>()) as u64;
-        for i in 0..self.free_blocks.len() {
-            if self.free_blocks[i].size >= size {
-                let block = self.free_blocks[i];
-                if block.size == size {
-                    self.free_blocks.swap_remove(i);
-                } else {
-                    self.free_blocks[i].offset += size;
-                    self.free_blocks[i].size -= size;
-                }
-                return BufferLocation { offset: block.offset, size };
-            }
+        if let Some(loc) = self.allocator.allocate(size) {
+            return Ok(loc);
+        }
+        self.grow(cmd, size)?;
+        return Ok(self.allocator.allocate(size).expect("grown face buffer fits the allocation"));
+    }
+
+    fn grow(&mut self, cmd: &mut CommandBuffer, size: u64) -> Result<(), FaceBufferError> {
+        // free space at the end joins up with the new space
+        let capacity = self.allocator.capacity();
+        let required = capacity + size - self.allocator.free_tail();
+        if required > self.max_capacity {
+            return Err(FaceBufferError::OutOfMemory { requested: size, max: self.max_capacity });
+        }
+        let new_capacity = (capacity * 2).max(required).min(self.max_capacity);
+
+        let regions: Vec<BufferCopy> = self
+            .allocator
+            .allocations()
+            .map(|loc| BufferCopy {
+                src_offset: loc.offset,
+                dst_offset: loc.offset,
+                size: loc.size,
+            })
+            .collect();
+
+        let buffer = create_face_buffer(new_capacity);
+        // uploads already recorded into cmd have to land before they are copied
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::TransferWrite],
+            next_accesses: &[AccessType::TransferRead],
+        });
+        if !regions.is_empty() {
+            cmd.copy_buffer(&self.buffer, &buffer, &regions);
         }
-        panic!("FaceBuffer: out of pre-allocated memory");
+        // uploads recorded after this go into the new buffer and must not be overwritten by the copy
+        cmd.global_barrier(&GlobalBarrier {
+            previous_accesses: &[AccessType::TransferWrite],
+            next_accesses: &[AccessType::TransferWrite],
+        });
+
+        self.retired.retire(self.buffer);
+        self.buffer = buffer;
+        self.allocator.grow(new_capacity);
+        return Ok(());
     }
 
     pub fn free(&mut self, loc: BufferLocation) {
-        self.free_blocks.push(FreeBlock { offset: loc.offset, size: loc.size });
-        self.coalesce();
+        self.allocator.free(loc);
     }
 
-    pub fn raw(&self) -> Buffer {
-        self.buffer
+    // the mesh furthest into the buffer that starts before offset, compaction walks down the buffer with it
+    pub fn prev_allocation(&self, offset: u64) -> Option<BufferLocation> {
+        return self.allocator.prev_allocation(offset);
     }
 
-    fn coalesce(&mut self) {
-        self.free_blocks.sort_by_key(|b| b.offset);
-        let mut i = 0;
-        while i + 1 < self.free_blocks.len() {
-            let end = self.free_blocks[i].offset + self.free_blocks[i].size;
-            if end >= self.free_blocks[i + 1].offset {
-                let next_end = self.free_blocks[i + 1].offset + self.free_blocks[i + 1].size;
-                self.free_blocks[i].size = next_end - self.free_blocks[i].offset;
-                self.free_blocks.remove(i + 1);
-            } else {
-                i += 1;
-            }
-        }
+    // meshes before this offset are packed, moving them can't close any hole
+    pub fn first_free_offset(&self) -> Option<u64> {
+        return self.allocator.first_free_offset();
+    }
+
+    // the lowest free range that fits a mesh of loc's size and ends before loc, if there is one.
+    // loc stays allocated until the caller frees it once nothing reads it anymore
+    pub fn allocate_below(&mut self, loc: BufferLocation) -> Option<BufferLocation> {
+        return self.allocator.allocate_lowest(loc.size, loc.offset);
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        return self.allocator.stats();
+    }
+
+    // how big the buffer is allowed to grow
+    pub fn max_capacity(&self) -> u64 {
+        return self.max_capacity;
+    }
+
+    // old buffers can be destroyed once this submission, which comes after every use of them, is done
+    pub fn retire_after(&mut self, counter: Counter) {
+        self.retired.retire_after(counter);
+    }
+
+    pub fn destroy_retired(&mut self) {
+        self.retired.destroy_finished();
+    }
+
+    pub fn raw(&self) -> Buffer {
+        self.buffer
     }
 }
 
@@ -0,0 +1,35 @@
+// helpers shared by the unit tests
+use crate::application::BLOCKS_PATH;
+use crate::chunk::BlockRegistry;
+use std::path::PathBuf;
+
+pub fn registry() -> BlockRegistry {
+    return BlockRegistry::load(BLOCKS_PATH).unwrap();
+}
+
+// a fresh directory per test, tests run in parallel
+pub fn temp_dir(area: &str, name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("minceraft-{area}-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    return dir;
+}
+
+// xorshift, the sequences only have to be repeatable so a failure can be reproduced from its seed
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return self.0;
+    }
+
+    pub fn below(&mut self, max: u64) -> u64 {
+        return self.next() % max;
+    }
+
+    pub fn chance(&mut self, percent: u64) -> bool {
+        return self.below(100) < percent;
+    }
+}
@@ -0,0 +1,405 @@
+use super::ChunkStore;
+use crate::chunk::{Block, Chunk};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// chunks are spread over this many independently locked shards
+const SHARD_COUNT: usize = 16;
+
+struct CacheEntry {
+    blocks: Arc<Chunk>,
+    // changed since it was last written to the chunk store
+    dirty: bool,
+    size: usize,
+    // key in the lru, only there while the chunk is not pinned
+    last_used: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub chunks: usize,
+    pub pinned: usize,
+    pub bytes: usize,
+}
+
+struct Shard {
+    entries: HashMap<(i32, i32, i32), CacheEntry>,
+    // chunks that can't be evicted and how many users each one has.
+    // a chunk can be pinned before it is in the cache
+    pins: HashMap<(i32, i32, i32), u32>,
+    // unpinned chunks, least recently used first
+    lru: BTreeMap<u64, (i32, i32, i32)>,
+    clock: u64,
+    bytes: usize,
+    // bytes of the unpinned chunks, the only ones the budget applies to
+    spare_bytes: usize,
+}
+
+impl Shard {
+    fn is_pinned(&self, coords: (i32, i32, i32)) -> bool {
+        return self.pins.contains_key(&coords);
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        return self.clock;
+    }
+
+    fn insert(&mut self, coords: (i32, i32, i32), blocks: Arc<Chunk>) {
+        let size = blocks.memory_usage();
+        let last_used = self.tick();
+        if !self.is_pinned(coords) {
+            self.lru.insert(last_used, coords);
+            self.spare_bytes += size;
+        }
+        self.entries.insert(coords, CacheEntry { blocks, dirty: false, size, last_used });
+        self.bytes += size;
+    }
+
+    fn set_block(&mut self, coords: (i32, i32, i32), index: usize, block: Block) -> Option<bool> {
+        let pinned = self.is_pinned(coords);
+        let entry = self.entries.get_mut(&coords)?;
+        if entry.blocks.get(index) == block {
+            return Some(false);
+        }
+
+        // copy on write, workers might still be meshing the old blocks
+        let chunk = Arc::make_mut(&mut entry.blocks);
+        chunk.set(index, block);
+        entry.dirty = true;
+
+        // the palette can grow
+        let size = chunk.memory_usage();
+        self.bytes = self.bytes - entry.size + size;
+        if !pinned {
+            self.spare_bytes = self.spare_bytes - entry.size + size;
+        }
+        entry.size = size;
+        return Some(true);
+    }
+
+    fn touch(&mut self, coords: (i32, i32, i32)) {
+        if self.is_pinned(coords) || !self.entries.contains_key(&coords) {
+            return;
+        }
+        let tick = self.tick();
+        let entry = self.entries.get_mut(&coords).unwrap();
+        self.lru.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.lru.insert(tick, coords);
+    }
+
+    fn pin(&mut self, coords: (i32, i32, i32)) {
+        let pins = self.pins.entry(coords).or_insert(0);
+        *pins += 1;
+        if *pins == 1
+            && let Some(entry) = self.entries.get(&coords)
+        {
+            self.lru.remove(&entry.last_used);
+            self.spare_bytes -= entry.size;
+        }
+    }
+
+    // returns true if that was the last pin
+    fn unpin(&mut self, coords: (i32, i32, i32)) -> bool {
+        let Some(pins) = self.pins.get_mut(&coords) else {
+            return false;
+        };
+        *pins -= 1;
+        if *pins > 0 {
+            return false;
+        }
+        self.pins.remove(&coords);
+
+        let tick = self.tick();
+        if let Some(entry) = self.entries.get_mut(&coords) {
+            entry.last_used = tick;
+            self.lru.insert(tick, coords);
+            self.spare_bytes += entry.size;
+        }
+        return true;
+    }
+
+    fn take(&mut self, coords: (i32, i32, i32)) -> Option<CacheEntry> {
+        let entry = self.entries.remove(&coords)?;
+        if !self.is_pinned(coords) {
+            self.lru.remove(&entry.last_used);
+            self.spare_bytes -= entry.size;
+        }
+        self.bytes -= entry.size;
+        return Some(entry);
+    }
+
+    // edited chunks are saved before the shard is unlocked, otherwise a worker could load the old
+    // version from the store in between. chunks that fail to save stay in memory
+    fn evict_all(&mut self, store: &ChunkStore, coords: Vec<(i32, i32, i32)>) {
+        for coords in coords {
+            if let Some(entry) = self.entries.get(&coords)
+                && entry.dirty
+                && let Err(e) = store.save(coords, &entry.blocks)
+            {
+                eprintln!("Failed to save chunk {coords:?}, keeping it in memory: {e}");
+                continue;
+            }
+            self.take(coords);
+        }
+    }
+
+    fn evict_outside(&mut self, store: &ChunkStore, center: (i32, i32, i32), radius: i32) {
+        let outside = self
+            .lru
+            .values()
+            .filter(|c| {
+                let dx = c.0 - center.0;
+                let dy = c.1 - center.1;
+                let dz = c.2 - center.2;
+                dx * dx + dy * dy + dz * dz > radius * radius
+            })
+            .copied()
+            .collect();
+        self.evict_all(store, outside);
+    }
+
+    // evicts unpinned chunks, least recently used first, until they fit
+    fn shrink_to(&mut self, store: &ChunkStore, max_spare_bytes: usize) {
+        let mut evicted = Vec::new();
+        let mut spare_bytes = self.spare_bytes;
+        for coords in self.lru.values() {
+            if spare_bytes <= max_spare_bytes {
+                break;
+            }
+            spare_bytes -= self.entries[coords].size;
+            evicted.push(*coords);
+        }
+        self.evict_all(store, evicted);
+    }
+}
+
+// blocks of every chunk in memory, shared between the world and the workers.
+// split into shards so workers touching different chunks rarely wait on each other.
+// pinned chunks (tracked by the world or needed by a job) stay in memory,
+// the rest are evicted least recently used first once they go over budget.
+pub struct ChunkCache {
+    shards: Box<[Mutex<Shard>]>,
+    store: Arc<ChunkStore>,
+    // per shard, only counts unpinned chunks
+    shard_budget: usize,
+}
+
+impl ChunkCache {
+    pub fn new(store: Arc<ChunkStore>, spare_bytes: usize) -> ChunkCache {
+        let shards = (0..SHARD_COUNT)
+            .map(|_| {
+                Mutex::new(Shard {
+                    entries: HashMap::new(),
+                    pins: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    clock: 0,
+                    bytes: 0,
+                    spare_bytes: 0,
+                })
+            })
+            .collect();
+
+        return ChunkCache {
+            shards,
+            store,
+            shard_budget: spare_bytes / SHARD_COUNT,
+        };
+    }
+
+    fn shard(&self, coords: (i32, i32, i32)) -> MutexGuard<'_, Shard> {
+        let hash = (coords.0 as u32).wrapping_mul(73856093) ^ (coords.1 as u32).wrapping_mul(19349663) ^ (coords.2 as u32).wrapping_mul(83492791);
+        return self.shards[hash as usize % SHARD_COUNT].lock().unwrap();
+    }
+
+    pub fn get(&self, coords: (i32, i32, i32)) -> Option<Arc<Chunk>> {
+        let mut shard = self.shard(coords);
+        shard.touch(coords);
+        return shard.entries.get(&coords).map(|e| e.blocks.clone());
+    }
+
+    pub fn contains(&self, coords: (i32, i32, i32)) -> bool {
+        return self.shard(coords).entries.contains_key(&coords);
+    }
+
+    // returns None if the chunk is not in memory, otherwise whether the block changed
+    pub fn set_block(&self, coords: (i32, i32, i32), index: usize, block: Block) -> Option<bool> {
+        return self.shard(coords).set_block(coords, index, block);
+    }
+
+    // adds a chunk as it is on disk, or as generated if it was never saved.
+    // keeps the chunk that is already there if another thread inserted it first
+    pub fn insert(&self, coords: (i32, i32, i32), chunk: Chunk) -> Arc<Chunk> {
+        let mut shard = self.shard(coords);
+        if let Some(existing) = shard.entries.get(&coords) {
+            return existing.blocks.clone();
+        }
+
+        let blocks = Arc::new(chunk);
+        shard.insert(coords, blocks.clone());
+        shard.shrink_to(&self.store, self.shard_budget);
+        return blocks;
+    }
+
+    pub fn pin(&self, coords: (i32, i32, i32)) {
+        self.shard(coords).pin(coords);
+    }
+
+    pub fn unpin(&self, coords: (i32, i32, i32)) {
+        let mut shard = self.shard(coords);
+        if shard.unpin(coords) {
+            shard.shrink_to(&self.store, self.shard_budget);
+        }
+    }
+
+    // unpins the chunk and evicts it right away, unless a job still needs it
+    pub fn release(&self, coords: (i32, i32, i32)) {
+        let mut shard = self.shard(coords);
+        if shard.unpin(coords) {
+            shard.evict_all(&self.store, vec![coords]);
+        }
+    }
+
+    // evicts every unpinned chunk further than radius chunks from center
+    pub fn evict_outside(&self, center: (i32, i32, i32), radius: i32) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().evict_outside(&self.store, center, radius);
+        }
+    }
+
+    // writes every dirty chunk to the chunk store
+    pub fn flush(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            for (coords, entry) in shard.entries.iter_mut() {
+                if !entry.dirty {
+                    continue;
+                }
+                match self.store.save(*coords, &entry.blocks) {
+                    Ok(()) => entry.dirty = false,
+                    Err(e) => eprintln!("Failed to save chunk {coords:?}: {e}"),
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats { chunks: 0, pinned: 0, bytes: 0 };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            stats.chunks += shard.entries.len();
+            stats.pinned += shard.pins.len();
+            stats.bytes += shard.bytes;
+        }
+        return stats;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    fn test_store(name: &str) -> (Arc<ChunkStore>, std::path::PathBuf) {
+        let dir = temp_dir("cache", name);
+        return (Arc::new(ChunkStore::new(&dir).unwrap()), dir);
+    }
+
+    #[test]
+    fn pinned_chunks_stay_and_unpinned_ones_are_evicted() {
+        let (store, dir) = test_store("pins");
+        let cache = ChunkCache::new(store.clone(), 0);
+
+        cache.pin((0, 0, 0));
+        cache.insert((0, 0, 0), Chunk::filled(Block::new(1)));
+        cache.insert((1, 0, 0), Chunk::filled(Block::new(1)));
+        assert!(cache.get((1, 0, 0)).is_none());
+        assert!(cache.get((0, 0, 0)).is_some());
+
+        // the second pin keeps it around after the first release
+        cache.pin((0, 0, 0));
+        cache.release((0, 0, 0));
+        assert!(cache.contains((0, 0, 0)));
+        cache.release((0, 0, 0));
+        assert!(!cache.contains((0, 0, 0)));
+        assert_eq!(cache.stats().chunks, 0);
+        assert_eq!(cache.stats().bytes, 0);
+
+        let cache = ChunkCache::new(store.clone(), 1 << 30);
+        for x in 0..10 {
+            cache.insert((x, 0, 0), Chunk::filled(Block::new(1)));
+        }
+        cache.pin((9, 0, 0));
+        cache.evict_outside((0, 0, 0), 3);
+        assert_eq!(cache.stats().chunks, 5);
+        assert!(cache.contains((9, 0, 0)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_edited_chunks_are_saved_on_eviction() {
+        let (store, dir) = test_store("dirty");
+        let cache = ChunkCache::new(store.clone(), 0);
+
+        for x in 0..2 {
+            cache.pin((x, 0, 0));
+            cache.insert((x, 0, 0), Chunk::filled(Block::AIR));
+        }
+        assert_eq!(cache.set_block((1, 0, 0), 5, Block::new(3)), Some(true));
+        assert_eq!(cache.set_block((1, 0, 0), 5, Block::new(3)), Some(false));
+        assert_eq!(cache.set_block((2, 0, 0), 5, Block::new(3)), None);
+        cache.release((0, 0, 0));
+        cache.release((1, 0, 0));
+
+        assert!(store.load((0, 0, 0)).unwrap().is_none());
+        assert!(store.load((1, 0, 0)).unwrap().unwrap().get(5) == Block::new(3));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reloading_while_an_edit_is_evicted_never_sees_old_blocks() {
+        let (store, dir) = test_store("reload");
+        let cache = Arc::new(ChunkCache::new(store.clone(), 0));
+        let coords = (0, 0, 0);
+        let edits = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+
+        // loads the chunk whenever it is missing, like a generate job would
+        let loader = {
+            let (cache, store, edits, done) = (cache.clone(), store.clone(), edits.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Acquire) {
+                    let edited = edits.load(Ordering::Acquire);
+                    if cache.contains(coords) {
+                        continue;
+                    }
+                    let chunk = store.load(coords).unwrap().unwrap_or_else(|| Chunk::from_blocks(&std::array::from_fn(|i| Block::new(2 + i as u16 % 3))));
+                    let chunk = cache.insert(coords, chunk);
+                    for index in 0..edited {
+                        assert!(chunk.get(index) == Block::new(1), "edit {index} of {edited} was lost");
+                    }
+                }
+            })
+        };
+
+        // every edit is evicted and saved right away, so the chunk keeps getting reloaded.
+        // short runs everywhere make saving slow enough for the loader to run into it
+        for index in 0..500 {
+            cache.pin(coords);
+            while !cache.contains(coords) {
+                std::thread::yield_now();
+            }
+            assert_eq!(cache.set_block(coords, index, Block::new(1)), Some(true));
+            edits.store(index + 1, Ordering::Release);
+            cache.release(coords);
+        }
+        done.store(true, Ordering::Release);
+        loader.join().unwrap();
+
+        let saved = store.load(coords).unwrap().unwrap();
+        assert!((0..500).all(|i| saved.get(i) == Block::new(1)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
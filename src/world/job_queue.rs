@@ -0,0 +1,205 @@
+use super::WorkItem;
+use crate::chunk::CHUNK_SIDE;
+use crate::renderer::Frustum;
+use glam::Vec3;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// chunks outside the view frustum are treated as if they were this many times further away
+const OUT_OF_VIEW_PENALTY: u64 = 2;
+
+// where the player is and what they are looking at, used to order chunk jobs
+#[derive(Clone, Copy, PartialEq)]
+pub struct LoadFocus {
+    pub center: (i32, i32, i32),
+    pub frustum: Option<Frustum>,
+}
+
+impl LoadFocus {
+    // lower is more important
+    pub fn priority(&self, coords: (i32, i32, i32)) -> u64 {
+        let dx = (coords.0 - self.center.0) as i64;
+        let dy = (coords.1 - self.center.1) as i64;
+        let dz = (coords.2 - self.center.2) as i64;
+        let dist_sq = (dx * dx + dy * dy + dz * dz) as u64;
+
+        let visible = self.frustum.is_none_or(|f| {
+            let side = CHUNK_SIDE as f32;
+            let min = Vec3::new(coords.0 as f32, coords.1 as f32, coords.2 as f32) * side;
+            f.is_aabb_visible(min, min + Vec3::splat(side))
+        });
+
+        if visible {
+            return dist_sq;
+        }
+        return dist_sq * OUT_OF_VIEW_PENALTY * OUT_OF_VIEW_PENALTY;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Job {
+    // load or generate the blocks of a chunk
+    Generate((i32, i32, i32)),
+    // mesh a chunk whose blocks and neighbours are all in the cache
+    Mesh(WorkItem),
+}
+
+impl Job {
+    pub fn coords(&self) -> (i32, i32, i32) {
+        return match self {
+            Job::Generate(coords) => *coords,
+            Job::Mesh(item) => item.coords,
+        };
+    }
+}
+
+struct QueuedJob {
+    priority: u64,
+    seq: u64,
+    job: Job,
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        return self.priority == other.priority && self.seq == other.seq;
+    }
+}
+
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        return (self.priority, self.seq).cmp(&(other.priority, other.seq));
+    }
+}
+
+// chunk jobs ordered by priority, jobs with the same priority come out in the order they went in
+pub struct JobQueue {
+    heap: BinaryHeap<Reverse<QueuedJob>>,
+    focus: LoadFocus,
+    next_seq: u64,
+}
+
+impl JobQueue {
+    pub fn new(focus: LoadFocus) -> JobQueue {
+        return JobQueue { heap: BinaryHeap::new(), focus, next_seq: 0 };
+    }
+
+    pub fn push(&mut self, job: Job) {
+        let priority = self.focus.priority(job.coords());
+        self.heap.push(Reverse(QueuedJob { priority, seq: self.next_seq, job }));
+        self.next_seq += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<Job> {
+        return self.heap.pop().map(|Reverse(queued)| queued.job);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.heap.len();
+    }
+
+    // recomputes the priority of every queued job, unless the focus didn't change
+    pub fn set_focus(&mut self, focus: LoadFocus) {
+        if focus == self.focus {
+            return;
+        }
+        self.focus = focus;
+
+        let mut jobs = std::mem::take(&mut self.heap).into_vec();
+        for Reverse(queued) in jobs.iter_mut() {
+            queued.priority = focus.priority(queued.job.coords());
+        }
+        self.heap = BinaryHeap::from(jobs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Mat4;
+
+    fn generate(coords: (i32, i32, i32)) -> Job {
+        return Job::Generate(coords);
+    }
+
+    fn mesh(coords: (i32, i32, i32), epoch: u64) -> Job {
+        return Job::Mesh(WorkItem { coords, epoch });
+    }
+
+    fn drain(queue: &mut JobQueue) -> Vec<(i32, i32, i32)> {
+        return std::iter::from_fn(|| queue.pop()).map(|j| j.coords()).collect();
+    }
+
+    // looking down -z from the middle of chunk (0, 0, 0)
+    fn looking_down_z() -> Frustum {
+        let mut proj = Mat4::perspective_rh(90f32.to_radians(), 1.0, 0.1, 1000.0);
+        proj.y_axis.y *= -1.0;
+        let eye = Vec3::splat(CHUNK_SIDE as f32 * 0.5);
+        let view = Mat4::look_at_rh(eye, eye + Vec3::NEG_Z, Vec3::Y);
+        return Frustum::from_view_proj(&(proj * view));
+    }
+
+    #[test]
+    fn nearest_jobs_come_out_first() {
+        let mut queue = JobQueue::new(LoadFocus { center: (0, 0, 0), frustum: None });
+        for coords in [(5, 0, 0), (0, -2, 0), (0, 0, 0), (1, 1, 1), (0, 0, 4)] {
+            queue.push(generate(coords));
+        }
+        assert_eq!(queue.len(), 5);
+        assert_eq!(drain(&mut queue), vec![(0, 0, 0), (1, 1, 1), (0, -2, 0), (0, 0, 4), (5, 0, 0)]);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn jobs_in_view_come_before_jobs_out_of_view() {
+        let mut queue = JobQueue::new(LoadFocus {
+            center: (0, 0, 0),
+            frustum: Some(looking_down_z()),
+        });
+        // same distance, only (0, 0, -3) is in front of the camera
+        queue.push(generate((0, 0, 3)));
+        queue.push(generate((3, 0, 0)));
+        queue.push(generate((0, 0, -3)));
+        // out of view chunks still win when they are much closer
+        queue.push(generate((0, 0, 1)));
+        assert_eq!(drain(&mut queue), vec![(0, 0, 1), (0, 0, -3), (0, 0, 3), (3, 0, 0)]);
+    }
+
+    #[test]
+    fn equal_priorities_keep_their_order() {
+        let mut queue = JobQueue::new(LoadFocus { center: (0, 0, 0), frustum: None });
+        queue.push(mesh((2, 0, 0), 1));
+        queue.push(generate((0, 2, 0)));
+        queue.push(mesh((0, 0, -2), 7));
+        queue.push(generate((-2, 0, 0)));
+        assert_eq!(drain(&mut queue), vec![(2, 0, 0), (0, 2, 0), (0, 0, -2), (-2, 0, 0)]);
+
+        // still true after the priorities are recomputed
+        for coords in [(1, 0, 0), (0, 1, 0), (-1, 0, 0)] {
+            queue.push(generate(coords));
+        }
+        queue.set_focus(LoadFocus { center: (0, 0, 1), frustum: None });
+        assert_eq!(drain(&mut queue), vec![(1, 0, 0), (0, 1, 0), (-1, 0, 0)]);
+    }
+
+    #[test]
+    fn moving_the_focus_reorders_queued_jobs() {
+        let mut queue = JobQueue::new(LoadFocus { center: (0, 0, 0), frustum: None });
+        for coords in [(5, 0, 0), (1, 0, 0), (0, 0, 0), (-3, 0, 0), (10, 0, 0)] {
+            queue.push(generate(coords));
+        }
+        assert_eq!(queue.pop().map(|j| j.coords()), Some((0, 0, 0)));
+
+        queue.set_focus(LoadFocus { center: (10, 0, 0), frustum: None });
+        // jobs pushed after the move use the new focus too
+        queue.push(generate((9, 0, 0)));
+        assert_eq!(drain(&mut queue), vec![(10, 0, 0), (9, 0, 0), (5, 0, 0), (1, 0, 0), (-3, 0, 0)]);
+    }
+}
@@ -1,11 +1,20 @@
+mod chunk_cache;
+mod job_queue;
+mod raycast;
+mod region;
 mod worker_pool;
 
+pub use chunk_cache::*;
+pub use job_queue::*;
+pub use raycast::*;
+pub use region::*;
 pub use worker_pool::*;
 
-use crate::chunk::{Block, CHUNK_VOLUME};
+use crate::chunk::{Block, BlockRegistry, CHUNK_SIDE, Chunk};
 use crate::renderer::BufferLocation;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use glam::Vec3;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum ChunkState {
@@ -17,6 +26,8 @@ struct ChunkEntry {
     state: ChunkState,
     face_loc: Option<BufferLocation>,
     cmd_slot: Option<usize>,
+    // bumped every time the chunk is (re)submitted, results with an older epoch are stale
+    epoch: u64,
 }
 
 pub struct ChunkUnloadInfo {
@@ -25,28 +36,69 @@ pub struct ChunkUnloadInfo {
     pub cmd_slot: usize,
 }
 
+// splits a world block position into chunk coords and the index inside the chunk
+pub fn split_world_pos(pos: (i32, i32, i32)) -> ((i32, i32, i32), (usize, usize, usize)) {
+    let side = CHUNK_SIDE as i32;
+    let chunk = (pos.0.div_euclid(side), pos.1.div_euclid(side), pos.2.div_euclid(side));
+    let local = (pos.0.rem_euclid(side) as usize, pos.1.rem_euclid(side) as usize, pos.2.rem_euclid(side) as usize);
+    return (chunk, local);
+}
+
 pub struct World {
     chunks: HashMap<(i32, i32, i32), ChunkEntry>,
     generation_radius: i32,
     unload_radius: i32,
-    chunk_cache: Arc<Mutex<HashMap<(i32, i32, i32), Arc<[Block; CHUNK_VOLUME]>>>>,
+    // the radius the world was created with, shrink_radius can only take it below this
+    max_generation_radius: i32,
+    chunk_cache: Arc<ChunkCache>,
+    store: Arc<ChunkStore>,
+    // spare chunks are only swept when the player crosses into another chunk
+    last_center: Option<(i32, i32, i32)>,
+    // chunks whose blocks changed since the last take_remesh
+    remesh: HashSet<(i32, i32, i32)>,
+    // chunks dropped since the last take_cancelled, their jobs can be skipped
+    cancelled: Vec<(i32, i32, i32)>,
+    // which chunk owns the mesh at each face buffer offset
+    face_owners: HashMap<u64, (i32, i32, i32)>,
+    next_epoch: u64,
 }
 
 impl World {
-    pub fn new(generation_radius: u32, unload_radius: u32) -> Self {
+    pub fn new(generation_radius: u32, unload_radius: u32, store: Arc<ChunkStore>, cache_bytes: usize) -> Self {
         return World {
             chunks: HashMap::new(),
             generation_radius: generation_radius as i32,
             unload_radius: unload_radius as i32,
-            chunk_cache: Arc::new(Mutex::new(HashMap::new())),
+            max_generation_radius: generation_radius as i32,
+            chunk_cache: Arc::new(ChunkCache::new(store.clone(), cache_bytes)),
+            store,
+            last_center: None,
+            remesh: HashSet::new(),
+            cancelled: Vec::new(),
+            face_owners: HashMap::new(),
+            next_epoch: 0,
         };
     }
 
-    pub fn chunk_cache(&self) -> Arc<Mutex<HashMap<(i32, i32, i32), Arc<[Block; CHUNK_VOLUME]>>>> {
+    pub fn chunk_cache(&self) -> Arc<ChunkCache> {
         self.chunk_cache.clone()
     }
 
-    pub fn update(&mut self, cx: i32, cy: i32, cz: i32) -> (Vec<(i32, i32, i32)>, Vec<ChunkUnloadInfo>) {
+    pub fn store(&self) -> Arc<ChunkStore> {
+        self.store.clone()
+    }
+
+    // writes every dirty chunk in the cache to the chunk store
+    pub fn flush(&self) {
+        self.chunk_cache.flush();
+    }
+
+    fn next_epoch(&mut self) -> u64 {
+        self.next_epoch += 1;
+        return self.next_epoch;
+    }
+
+    pub fn update(&mut self, cx: i32, cy: i32, cz: i32) -> (Vec<WorkItem>, Vec<ChunkUnloadInfo>) {
         let mut to_load = Vec::new();
         let mut to_unload = Vec::new();
 
@@ -66,7 +118,10 @@ impl World {
 
         for key in unload_keys {
             if let Some(entry) = self.chunks.remove(&key) {
-                self.chunk_cache.lock().unwrap().remove(&key);
+                self.chunk_cache.release(key);
+                self.remesh.remove(&key);
+                self.cancelled.push(key);
+                self.face_owners.remove(&entry.face_loc.unwrap().offset);
                 to_unload.push(ChunkUnloadInfo {
                     coords: key,
                     face_loc: entry.face_loc.unwrap(),
@@ -75,11 +130,12 @@ impl World {
             }
         }
 
-        let empty_unload: Vec<(i32, i32, i32)> = self
+        // chunks without a mesh, either empty or still waiting on a worker
+        let meshless_unload: Vec<(i32, i32, i32)> = self
             .chunks
             .iter()
             .filter(|(key, entry)| {
-                entry.state == ChunkState::Loaded && entry.face_loc.is_none() && {
+                entry.face_loc.is_none() && {
                     let dx = key.0 - cx;
                     let dy = key.1 - cy;
                     let dz = key.2 - cz;
@@ -89,9 +145,17 @@ impl World {
             .map(|(&key, _)| key)
             .collect();
 
-        for key in empty_unload {
+        for key in meshless_unload {
             self.chunks.remove(&key);
-            self.chunk_cache.lock().unwrap().remove(&key);
+            self.chunk_cache.release(key);
+            self.remesh.remove(&key);
+            self.cancelled.push(key);
+        }
+
+        // neighbours loaded only for meshing are not tracked, drop the ones that fell behind
+        if self.last_center != Some((cx, cy, cz)) {
+            self.chunk_cache.evict_outside((cx, cy, cz), self.unload_radius);
+            self.last_center = Some((cx, cy, cz));
         }
 
         for dz in -self.generation_radius..=self.generation_radius {
@@ -103,15 +167,18 @@ impl World {
                     let coords = (cx + dx, cy + dy, cz + dz);
 
                     if !self.chunks.contains_key(&coords) {
+                        let epoch = self.next_epoch();
+                        self.chunk_cache.pin(coords);
                         self.chunks.insert(
                             coords,
                             ChunkEntry {
                                 state: ChunkState::Pending,
                                 face_loc: None,
                                 cmd_slot: None,
+                                epoch,
                             },
                         );
-                        to_load.push(coords);
+                        to_load.push(WorkItem { coords, epoch });
                     }
                 }
             }
@@ -120,17 +187,339 @@ impl World {
         return (to_load, to_unload);
     }
 
-    pub fn mark_loaded(&mut self, coords: (i32, i32, i32), face_loc: BufferLocation, cmd_slot: usize) {
+    // pulls the generation and unload radius in by a chunk, returns the new generation radius.
+    // chunks past the new unload radius are dropped by the next update
+    pub fn shrink_radius(&mut self) -> u32 {
+        if self.generation_radius > 1 {
+            self.generation_radius -= 1;
+            self.unload_radius -= 1;
+        }
+        return self.generation_radius as u32;
+    }
+
+    // how many chunks grow_radius adds to the generation radius, some might still be loaded from before.
+    // None if the radius is already back to where it started, or while chunks are still loading
+    // since their meshes don't take up space yet
+    pub fn radius_growth(&self) -> Option<usize> {
+        if self.generation_radius >= self.max_generation_radius || self.chunks.values().any(|e| e.state == ChunkState::Pending) {
+            return None;
+        }
+        let (r, next) = (self.generation_radius, self.generation_radius + 1);
+        let mut count = 0;
+        for dz in -next..=next {
+            for dx in -next..=next {
+                for dy in -next..=next {
+                    let dist_sq = dx * dx + dy * dy + dz * dz;
+                    if dist_sq > r * r && dist_sq <= next * next {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        return Some(count);
+    }
+
+    // undoes a shrink_radius, returns the new generation radius
+    pub fn grow_radius(&mut self) -> u32 {
+        if self.generation_radius < self.max_generation_radius {
+            self.generation_radius += 1;
+            self.unload_radius += 1;
+        }
+        return self.generation_radius as u32;
+    }
+
+    // meshes the chunk again with the next take_remesh, e.g. when its mesh couldn't be uploaded
+    pub fn retry_mesh(&mut self, coords: (i32, i32, i32)) {
+        if self.chunks.contains_key(&coords) {
+            self.remesh.insert(coords);
+        }
+    }
+
+    // false if the chunk was unloaded or resubmitted after this job was created
+    pub fn is_current(&self, coords: (i32, i32, i32), epoch: u64) -> bool {
+        return self.chunks.get(&coords).is_some_and(|e| e.epoch == epoch);
+    }
+
+    // returns the mesh this one replaces, if the chunk was remeshed
+    pub fn mark_loaded(&mut self, coords: (i32, i32, i32), face_loc: BufferLocation, cmd_slot: usize) -> Option<ChunkUnloadInfo> {
+        let entry = self.chunks.get_mut(&coords)?;
+        let previous = World::take_mesh(coords, entry);
+        entry.state = ChunkState::Loaded;
+        entry.face_loc = Some(face_loc);
+        entry.cmd_slot = Some(cmd_slot);
+        if let Some(previous) = &previous {
+            self.face_owners.remove(&previous.face_loc.offset);
+        }
+        self.face_owners.insert(face_loc.offset, coords);
+        return previous;
+    }
+
+    pub fn mark_loaded_empty(&mut self, coords: (i32, i32, i32)) -> Option<ChunkUnloadInfo> {
+        let entry = self.chunks.get_mut(&coords)?;
+        entry.state = ChunkState::Loaded;
+        let previous = World::take_mesh(coords, entry);
+        if let Some(previous) = &previous {
+            self.face_owners.remove(&previous.face_loc.offset);
+        }
+        return previous;
+    }
+
+    // the draw command slot of the chunk's current mesh
+    pub fn cmd_slot(&self, coords: (i32, i32, i32)) -> Option<usize> {
+        return self.chunks.get(&coords).and_then(|e| e.cmd_slot);
+    }
+
+    // the chunk's draw command was moved to another slot
+    pub fn remap_slot(&mut self, coords: (i32, i32, i32), cmd_slot: usize) {
         if let Some(entry) = self.chunks.get_mut(&coords) {
-            entry.state = ChunkState::Loaded;
-            entry.face_loc = Some(face_loc);
             entry.cmd_slot = Some(cmd_slot);
         }
     }
 
-    pub fn mark_loaded_empty(&mut self, coords: (i32, i32, i32)) {
-        if let Some(entry) = self.chunks.get_mut(&coords) {
-            entry.state = ChunkState::Loaded;
+    // the chunk whose current mesh starts at the face buffer offset and its draw command slot.
+    // None for meshes that were already unloaded or replaced
+    pub fn mesh_at(&self, face_offset: u64) -> Option<((i32, i32, i32), usize)> {
+        let coords = *self.face_owners.get(&face_offset)?;
+        return self.chunks.get(&coords).and_then(|e| e.cmd_slot).map(|slot| (coords, slot));
+    }
+
+    // the chunk's mesh was copied to another place in the face buffer
+    pub fn relocate_mesh(&mut self, coords: (i32, i32, i32), face_loc: BufferLocation) {
+        let Some(entry) = self.chunks.get_mut(&coords) else {
+            return;
+        };
+        if let Some(old) = entry.face_loc.replace(face_loc) {
+            self.face_owners.remove(&old.offset);
+        }
+        self.face_owners.insert(face_loc.offset, coords);
+    }
+
+    fn take_mesh(coords: (i32, i32, i32), entry: &mut ChunkEntry) -> Option<ChunkUnloadInfo> {
+        return match (entry.face_loc.take(), entry.cmd_slot.take()) {
+            (Some(face_loc), Some(cmd_slot)) => Some(ChunkUnloadInfo { coords, face_loc, cmd_slot }),
+            _ => None,
+        };
+    }
+
+    // None if the chunk holding pos is not in memory
+    pub fn get_block(&self, pos: (i32, i32, i32)) -> Option<Block> {
+        let (chunk, local) = split_world_pos(pos);
+        return self.chunk_cache.get(chunk).map(|c| c.get(Chunk::get_index(local.0, local.1, local.2)));
+    }
+
+    // returns false if the chunk holding pos is not in memory
+    pub fn set_block(&mut self, pos: (i32, i32, i32), block: Block) -> bool {
+        let (coords, local) = split_world_pos(pos);
+        match self.chunk_cache.set_block(coords, Chunk::get_index(local.0, local.1, local.2), block) {
+            None => return false,
+            Some(false) => return true,
+            Some(true) => {}
+        }
+
+        // blocks on the border change the faces and ao of the neighbouring chunks too, the ones
+        // across an edge or corner included
+        let last = CHUNK_SIDE - 1;
+        let sides = |l: usize| -> &[i32] {
+            if l == 0 {
+                return &[0, -1];
+            }
+            if l == last {
+                return &[0, 1];
+            }
+            return &[0];
+        };
+        for &dz in sides(local.2) {
+            for &dy in sides(local.1) {
+                for &dx in sides(local.0) {
+                    let c = (coords.0 + dx, coords.1 + dy, coords.2 + dz);
+                    if self.chunks.contains_key(&c) {
+                        self.remesh.insert(c);
+                    }
+                }
+            }
+        }
+
+        return true;
+    }
+
+    // fills the box between min and max, both inclusive. the remesh set collects every chunk
+    // touched, so each one is meshed once for the whole fill
+    #[allow(unused)]
+    pub fn fill_box(&mut self, min: (i32, i32, i32), max: (i32, i32, i32), block: Block) {
+        for z in min.2..=max.2 {
+            for y in min.1..=max.1 {
+                for x in min.0..=max.0 {
+                    self.set_block((x, y, z), block);
+                }
+            }
         }
     }
+
+    #[allow(unused)]
+    pub fn fill_sphere(&mut self, center: (i32, i32, i32), radius: i32, block: Block) {
+        for dz in -radius..=radius {
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    if dx * dx + dy * dy + dz * dz <= radius * radius {
+                        self.set_block((center.0 + dx, center.1 + dy, center.2 + dz), block);
+                    }
+                }
+            }
+        }
+    }
+
+    // finds the first solid block along the ray, blocks that are not in memory count as empty
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32, registry: &BlockRegistry) -> Option<RaycastHit> {
+        return raycast(origin, dir, max_dist, |pos| self.get_block(pos).and_then(|b| registry.definition(b)).is_some_and(|d| d.solid));
+    }
+
+    // chunks that need to be meshed again, each one only once no matter how many edits it got
+    pub fn take_remesh(&mut self) -> Vec<WorkItem> {
+        let mut items = Vec::with_capacity(self.remesh.len());
+        for coords in std::mem::take(&mut self.remesh) {
+            let epoch = self.next_epoch();
+            if let Some(entry) = self.chunks.get_mut(&coords) {
+                entry.epoch = epoch;
+                items.push(WorkItem { coords, epoch });
+            }
+        }
+        return items;
+    }
+
+    pub fn take_cancelled(&mut self) -> Vec<(i32, i32, i32)> {
+        return std::mem::take(&mut self.cancelled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+    use std::path::PathBuf;
+
+    // a world around the origin whose chunks are all air and already in the cache
+    fn test_world(name: &str) -> (World, PathBuf) {
+        let dir = temp_dir("world", name);
+        let mut world = World::new(2, 3, Arc::new(ChunkStore::new(&dir).unwrap()), 0);
+        let (to_load, _) = world.update(0, 0, 0);
+        for item in to_load {
+            world.chunk_cache.insert(item.coords, Chunk::filled(Block::AIR));
+        }
+        return (world, dir);
+    }
+
+    fn remeshed(world: &mut World) -> HashSet<(i32, i32, i32)> {
+        return world.take_remesh().into_iter().map(|item| item.coords).collect();
+    }
+
+    #[test]
+    fn edits_copy_the_chunk_instead_of_changing_it_under_readers() {
+        let (mut world, dir) = test_world("copy");
+        let stone = Block::new(5);
+        let before = world.chunk_cache.get((0, 0, 0)).unwrap();
+        assert!(world.set_block((3, 4, 5), stone));
+
+        assert!(before.get(Chunk::get_index(3, 4, 5)).is_air());
+        let after = world.chunk_cache.get((0, 0, 0)).unwrap();
+        assert!(after.get(Chunk::get_index(3, 4, 5)) == stone);
+        assert!(!Arc::ptr_eq(&before, &after));
+
+        // only the edited chunk is written
+        world.flush();
+        assert!(world.store.load((0, 0, 0)).unwrap().is_some());
+        assert!(world.store.load((1, 0, 0)).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_remesh_every_chunk_that_sees_the_block() {
+        let (mut world, dir) = test_world("remesh");
+        let stone = Block::new(5);
+        remeshed(&mut world);
+
+        // inside the chunk
+        assert!(world.set_block((10, 10, 10), stone));
+        assert_eq!(remeshed(&mut world), HashSet::from([(0, 0, 0)]));
+
+        // the same block again changes nothing
+        assert!(world.set_block((10, 10, 10), stone));
+        assert!(remeshed(&mut world).is_empty());
+
+        // on a face, an edge and a corner of the chunk
+        assert!(world.set_block((0, 10, 10), stone));
+        assert_eq!(remeshed(&mut world), HashSet::from([(0, 0, 0), (-1, 0, 0)]));
+        assert!(world.set_block((31, 31, 10), stone));
+        assert_eq!(remeshed(&mut world), HashSet::from([(0, 0, 0), (1, 0, 0), (0, 1, 0), (1, 1, 0)]));
+        assert!(world.set_block((-1, -1, -1), stone));
+        let corner: HashSet<(i32, i32, i32)> = (0..8).map(|i| (-(i & 1), -(i >> 1 & 1), -(i >> 2 & 1))).collect();
+        assert_eq!(remeshed(&mut world), corner);
+
+        // several edits to the same chunks are meshed once
+        for x in 0..32 {
+            world.set_block((x, 5, 5), stone);
+        }
+        assert_eq!(world.take_remesh().len(), 3);
+
+        // chunks that aren't in memory can't be edited
+        assert!(!world.set_block((1000, 0, 0), stone));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fills_across_a_border_mesh_each_chunk_once() {
+        let (mut world, dir) = test_world("fill");
+        let stone = Block::new(5);
+        remeshed(&mut world);
+
+        // x from 28 to 35 crosses from chunk 0 into chunk 1, y and z stay inside
+        world.fill_box((28, 4, 4), (35, 8, 8), stone);
+        assert!(world.get_block((28, 4, 4)) == Some(stone));
+        assert!(world.get_block((35, 8, 8)) == Some(stone));
+        assert!(world.get_block((36, 8, 8)) == Some(Block::AIR));
+        let items = world.take_remesh();
+        let coords: HashSet<(i32, i32, i32)> = items.iter().map(|item| item.coords).collect();
+        assert_eq!(items.len(), coords.len());
+        assert_eq!(coords, HashSet::from([(0, 0, 0), (1, 0, 0)]));
+
+        // a sphere on the corner between four chunks, its blocks on the borders also remesh the chunks across them
+        world.fill_sphere((0, 0, 10), 3, stone);
+        assert!(world.get_block((-3, 0, 10)) == Some(stone));
+        assert!(world.get_block((-3, -3, 10)) == Some(Block::AIR));
+        let items = world.take_remesh();
+        let coords: HashSet<(i32, i32, i32)> = items.iter().map(|item| item.coords).collect();
+        assert_eq!(items.len(), coords.len());
+        assert_eq!(coords, HashSet::from([(0, 0, 0), (-1, 0, 0), (0, -1, 0), (-1, -1, 0)]));
+
+        assert!(world.get_block((1000, 0, 0)).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_shrunk_radius_grows_back_to_where_it_started() {
+        let (mut world, dir) = test_world("radius");
+        // 33 chunks within 2 of the origin, 7 within 1
+        assert_eq!(world.chunks.len(), 33);
+        assert_eq!(world.radius_growth(), None);
+
+        assert_eq!(world.shrink_radius(), 1);
+        assert_eq!(world.shrink_radius(), 1);
+        // the unload radius went down to 2, the player moving away drops what is past it
+        world.update(1, 0, 0);
+        assert!(world.chunks.keys().all(|c| (c.0 - 1).pow(2) + c.1.pow(2) + c.2.pow(2) <= 4));
+
+        // not while chunks are still waiting for their meshes
+        assert_eq!(world.radius_growth(), None);
+        for coords in world.chunks.keys().copied().collect::<Vec<_>>() {
+            world.mark_loaded_empty(coords);
+        }
+        assert_eq!(world.radius_growth(), Some(33 - 7));
+
+        assert_eq!(world.grow_radius(), 2);
+        let (to_load, _) = world.update(1, 0, 0);
+        assert!(!to_load.is_empty());
+        assert_eq!(world.chunks.len(), 33);
+        assert_eq!(world.grow_radius(), 2);
+        assert_eq!(world.radius_growth(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,158 @@
+use glam::Vec3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RaycastHit {
+    // world position of the block that was hit
+    pub block: (i32, i32, i32),
+    // normal of the face the ray entered through, zero if the ray started inside the block
+    pub normal: (i32, i32, i32),
+    pub distance: f32,
+}
+
+// Amanatides-Woo voxel traversal, visits every block the ray passes through in order.
+pub fn raycast(origin: Vec3, dir: Vec3, max_dist: f32, mut is_solid: impl FnMut((i32, i32, i32)) -> bool) -> Option<RaycastHit> {
+    let dir = dir.normalize_or_zero();
+    if dir == Vec3::ZERO {
+        return None;
+    }
+
+    let origin = origin.to_array();
+    let dir = dir.to_array();
+
+    let mut pos = [0; 3];
+    let mut step = [0; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+
+    for axis in 0..3 {
+        let start = origin[axis].floor();
+        pos[axis] = start as i32;
+
+        if dir[axis] > 0.0 {
+            step[axis] = 1;
+            t_max[axis] = (start + 1.0 - origin[axis]) / dir[axis];
+            t_delta[axis] = 1.0 / dir[axis];
+        } else if dir[axis] < 0.0 {
+            step[axis] = -1;
+            t_max[axis] = (origin[axis] - start) / -dir[axis];
+            t_delta[axis] = 1.0 / -dir[axis];
+        }
+    }
+
+    if is_solid((pos[0], pos[1], pos[2])) {
+        return Some(RaycastHit {
+            block: (pos[0], pos[1], pos[2]),
+            normal: (0, 0, 0),
+            distance: 0.0,
+        });
+    }
+
+    loop {
+        let axis = if t_max[0] < t_max[1] {
+            if t_max[0] < t_max[2] { 0 } else { 2 }
+        } else if t_max[1] < t_max[2] {
+            1
+        } else {
+            2
+        };
+
+        let distance = t_max[axis];
+        if distance > max_dist {
+            return None;
+        }
+
+        pos[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+
+        if is_solid((pos[0], pos[1], pos[2])) {
+            let mut normal = [0; 3];
+            normal[axis] = -step[axis];
+
+            return Some(RaycastHit {
+                block: (pos[0], pos[1], pos[2]),
+                normal: (normal[0], normal[1], normal[2]),
+                distance,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec3;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{a} != {b}");
+    }
+
+    #[test]
+    fn axis_aligned_rays_hit_the_face_facing_them() {
+        let floor = |p: (i32, i32, i32)| p.1 < 0;
+        let hit = raycast(vec3(0.5, 5.5, 0.5), vec3(0.0, -1.0, 0.0), 10.0, floor).unwrap();
+        assert_eq!(hit.block, (0, -1, 0));
+        assert_eq!(hit.normal, (0, 1, 0));
+        assert_near(hit.distance, 5.5);
+
+        let wall = |p: (i32, i32, i32)| p == (4, 2, 0);
+        let hit = raycast(vec3(0.5, 2.5, 0.5), vec3(3.0, 0.0, 0.0), 10.0, wall).unwrap();
+        assert_eq!(hit.normal, (-1, 0, 0));
+        assert_near(hit.distance, 3.5);
+
+        let hit = raycast(vec3(0.5, 0.5, 9.5), vec3(0.0, 0.0, -1.0), 10.0, |p| p.2 <= 0).unwrap();
+        assert_eq!(hit.block, (0, 0, 0));
+        assert_eq!(hit.normal, (0, 0, 1));
+        assert_near(hit.distance, 8.5);
+    }
+
+    #[test]
+    fn diagonal_rays_visit_every_block_they_cross() {
+        let mut visited = Vec::new();
+        let hit = raycast(vec3(0.2, 0.3, 0.4), vec3(1.0, 1.0, 1.0), 20.0, |p| {
+            visited.push(p);
+            p == (5, 5, 5)
+        });
+        assert_eq!(hit.unwrap().block, (5, 5, 5));
+        // each step moves one block along one axis
+        for pair in visited.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert_eq!((b.0 - a.0).abs() + (b.1 - a.1).abs() + (b.2 - a.2).abs(), 1, "{a:?} -> {b:?}");
+        }
+        assert_eq!(visited.len(), 1 + 15);
+
+        // x is the closest boundary from (0.7, 0.1, 0.1), so a wall at x = 3 is entered through -x
+        let hit = raycast(vec3(0.7, 0.1, 0.1), vec3(1.0, 1.0, 1.0), 20.0, |p| p.0 >= 3).unwrap();
+        assert_eq!(hit.normal, (-1, 0, 0));
+        assert_near(hit.distance, 2.3 * 3f32.sqrt());
+    }
+
+    #[test]
+    fn rays_starting_inside_a_block_hit_it_right_away() {
+        let hit = raycast(vec3(0.5, 0.5, 0.5), vec3(1.0, 0.0, 0.0), 1.0, |p| p == (0, 0, 0)).unwrap();
+        assert_eq!(hit.block, (0, 0, 0));
+        assert_eq!(hit.normal, (0, 0, 0));
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn negative_coordinates_round_down() {
+        let hit = raycast(vec3(-0.5, -0.5, -0.5), vec3(-1.0, 0.0, 0.0), 10.0, |p| p == (-3, -1, -1)).unwrap();
+        assert_eq!(hit.block, (-3, -1, -1));
+        assert_eq!(hit.normal, (1, 0, 0));
+        assert_near(hit.distance, 1.5);
+
+        // starting exactly on a boundary belongs to the block on the positive side
+        let hit = raycast(vec3(-2.0, 0.5, 0.5), vec3(-1.0, 0.0, 0.0), 10.0, |p| p.0 == -3).unwrap();
+        assert_near(hit.distance, 0.0);
+        assert_eq!(hit.normal, (1, 0, 0));
+    }
+
+    #[test]
+    fn rays_stop_at_max_distance() {
+        let floor = |p: (i32, i32, i32)| p.1 < 0;
+        assert!(raycast(vec3(0.5, 5.5, 0.5), vec3(0.0, -1.0, 0.0), 5.0, floor).is_none());
+        assert!(raycast(vec3(0.5, 5.5, 0.5), vec3(0.0, -1.0, 0.0), 5.5, floor).is_some());
+        assert!(raycast(vec3(0.5, 5.5, 0.5), vec3(0.0, 1.0, 0.0), 100.0, floor).is_none());
+        assert!(raycast(vec3(0.5, 5.5, 0.5), Vec3::ZERO, 100.0, |_| true).is_none());
+    }
+}
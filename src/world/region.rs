@@ -0,0 +1,427 @@
+use crate::chunk::{Block, CHUNK_VOLUME, Chunk};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// region files hold REGION_SIDE^3 chunks.
+//
+// layout:
+// magic "MCRG" | version u32 | offset table | payloads.
+// the offset table has one (offset u32, length u32) entry per chunk, indexed like Chunk::get_index.
+// a length of 0 means the chunk was never saved.
+// a payload goes into the first unused range it fits in, or at the end of the file. a rewritten chunk's
+// old payload only becomes unused once the table points at the new one, so a crash never loses both.
+//
+// payload:
+// palette length u32 | palette ids u16 * len | runs of (length u16, palette index u16) covering the whole chunk.
+// everything is little endian.
+pub const REGION_SIDE: i32 = 32;
+pub const REGION_VOLUME: usize = (REGION_SIDE * REGION_SIDE * REGION_SIDE) as usize;
+
+const MAGIC: &[u8; 4] = b"MCRG";
+pub const REGION_VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 8 + REGION_VOLUME as u64 * 8;
+
+pub fn region_coords(coords: (i32, i32, i32)) -> ((i32, i32, i32), usize) {
+    let region = (coords.0.div_euclid(REGION_SIDE), coords.1.div_euclid(REGION_SIDE), coords.2.div_euclid(REGION_SIDE));
+    let local = Chunk::get_index(coords.0.rem_euclid(REGION_SIDE) as usize, coords.1.rem_euclid(REGION_SIDE) as usize, coords.2.rem_euclid(REGION_SIDE) as usize);
+    return (region, local);
+}
+
+fn read_u16(cursor: &mut &[u8]) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    cursor.read_exact(&mut bytes)?;
+    return Ok(u16::from_le_bytes(bytes));
+}
+
+pub fn encode_chunk(chunk: &Chunk) -> Vec<u8> {
+    let palette = chunk.palette();
+    let mut out = Vec::with_capacity(4 + palette.len() * 2 + 64);
+
+    out.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+    for block in palette {
+        out.extend_from_slice(&block.get_id().to_le_bytes());
+    }
+
+    let palette_index = |block: Block| palette.iter().position(|b| *b == block).unwrap() as u16;
+
+    let mut run_block = chunk.get(0);
+    let mut run_length: u16 = 0;
+    for i in 0..CHUNK_VOLUME {
+        let block = chunk.get(i);
+        if block != run_block || run_length == u16::MAX {
+            out.extend_from_slice(&run_length.to_le_bytes());
+            out.extend_from_slice(&palette_index(run_block).to_le_bytes());
+            run_block = block;
+            run_length = 0;
+        }
+        run_length += 1;
+    }
+    out.extend_from_slice(&run_length.to_le_bytes());
+    out.extend_from_slice(&palette_index(run_block).to_le_bytes());
+
+    return out;
+}
+
+pub fn decode_chunk(data: &[u8]) -> io::Result<Chunk> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut cursor = data;
+
+    let mut len_bytes = [0; 4];
+    cursor.read_exact(&mut len_bytes)?;
+    let palette_len = u32::from_le_bytes(len_bytes) as usize;
+    if palette_len == 0 || palette_len > CHUNK_VOLUME {
+        return Err(invalid("invalid palette length"));
+    }
+
+    let mut palette = Vec::with_capacity(palette_len);
+    for _ in 0..palette_len {
+        palette.push(Block::new(read_u16(&mut cursor)?));
+    }
+
+    let mut blocks = Box::new([Block::AIR; CHUNK_VOLUME]);
+    let mut i = 0;
+    while i < CHUNK_VOLUME {
+        let run_length = read_u16(&mut cursor)? as usize;
+        let index = read_u16(&mut cursor)? as usize;
+        let block = *palette.get(index).ok_or_else(|| invalid("palette index out of range"))?;
+        if run_length == 0 || i + run_length > CHUNK_VOLUME {
+            return Err(invalid("invalid run length"));
+        }
+        blocks[i..i + run_length].fill(block);
+        i += run_length;
+    }
+
+    // most saved chunks are all air or all stone, no need to scan them for their palette
+    if palette_len == 1 {
+        return Ok(Chunk::filled(palette[0]));
+    }
+    return Ok(Chunk::from_blocks(&blocks));
+}
+
+pub struct RegionFile {
+    file: File,
+    table: Vec<(u32, u32)>,
+    // unused ranges between payloads as (offset, length), sorted by offset
+    free: Vec<(u64, u64)>,
+    len: u64,
+}
+
+impl RegionFile {
+    pub fn open(path: &Path) -> io::Result<RegionFile> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+            header.extend_from_slice(MAGIC);
+            header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+            header.resize(HEADER_SIZE as usize, 0);
+            file.write_all(&header)?;
+
+            return Ok(RegionFile {
+                file,
+                table: vec![(0, 0); REGION_VOLUME],
+                free: Vec::new(),
+                len: HEADER_SIZE,
+            });
+        }
+
+        let mut header = vec![0; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+
+        if &header[0..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a region file"));
+        }
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != REGION_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported region version {version}")));
+        }
+
+        let table: Vec<(u32, u32)> = header[8..].chunks_exact(8).map(|e| (u32::from_le_bytes(e[0..4].try_into().unwrap()), u32::from_le_bytes(e[4..8].try_into().unwrap()))).collect();
+        let len = file.metadata()?.len();
+
+        // everything after the header that no payload covers is free. entries pointing outside the
+        // file fail when they are read and don't hold on to any space
+        let mut used: Vec<(u64, u64)> = table.iter().filter(|e| e.1 > 0).map(|e| (e.0 as u64, e.0 as u64 + e.1 as u64)).filter(|&(start, end)| start >= HEADER_SIZE && end <= len).collect();
+        used.sort_unstable();
+        let mut free = Vec::new();
+        let mut cursor = HEADER_SIZE;
+        for (start, end) in used {
+            if start > cursor {
+                free.push((cursor, start - cursor));
+            }
+            cursor = cursor.max(end);
+        }
+        if len > cursor {
+            free.push((cursor, len - cursor));
+        }
+
+        return Ok(RegionFile { file, table, free, len });
+    }
+
+    pub fn read(&mut self, local: usize) -> io::Result<Option<Vec<u8>>> {
+        let (offset, length) = self.table[local];
+        if length == 0 {
+            return Ok(None);
+        }
+        if (offset as u64) < HEADER_SIZE || offset as u64 + length as u64 > self.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk payload is outside the region file"));
+        }
+
+        let mut data = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(&mut data)?;
+        return Ok(Some(data));
+    }
+
+    pub fn write(&mut self, local: usize, data: &[u8]) -> io::Result<()> {
+        let offset = self.allocate(data.len() as u64)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+
+        // the table entry is only updated once the payload is written
+        let mut entry = [0; 8];
+        entry[0..4].copy_from_slice(&(offset as u32).to_le_bytes());
+        entry[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.file.seek(SeekFrom::Start(8 + local as u64 * 8))?;
+        self.file.write_all(&entry)?;
+
+        let (old_offset, old_length) = std::mem::replace(&mut self.table[local], (offset as u32, data.len() as u32));
+        if old_length > 0 && old_offset as u64 >= HEADER_SIZE && old_offset as u64 + old_length as u64 <= self.len {
+            self.release(old_offset as u64, old_length as u64);
+        }
+        return Ok(());
+    }
+
+    // the first unused range that fits, otherwise the end of the file
+    fn allocate(&mut self, size: u64) -> io::Result<u64> {
+        if let Some(i) = self.free.iter().position(|&(_, length)| length >= size) {
+            let (offset, length) = self.free[i];
+            if length == size {
+                self.free.remove(i);
+            } else {
+                self.free[i] = (offset + size, length - size);
+            }
+            return Ok(offset);
+        }
+
+        // a free range at the end of the file is extended instead of leaving it behind
+        let offset = match self.free.last() {
+            Some(&(offset, length)) if offset + length == self.len => offset,
+            _ => self.len,
+        };
+        if offset + size > u32::MAX as u64 {
+            return Err(io::Error::other("region file is full"));
+        }
+        if offset < self.len {
+            self.free.pop();
+        }
+        self.len = self.len.max(offset + size);
+        return Ok(offset);
+    }
+
+    fn release(&mut self, offset: u64, size: u64) {
+        let i = self.free.partition_point(|&(o, _)| o < offset);
+        self.free.insert(i, (offset, size));
+        // merge with the ranges right after and right before
+        if i + 1 < self.free.len() && offset + size == self.free[i + 1].0 {
+            self.free[i].1 += self.free[i + 1].1;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == offset {
+            self.free[i - 1].1 += self.free[i].1;
+            self.free.remove(i);
+        }
+    }
+}
+
+// loads and saves chunks to region files in a directory, shared between the world and the workers
+pub struct ChunkStore {
+    dir: PathBuf,
+    regions: Mutex<HashMap<(i32, i32, i32), RegionFile>>,
+}
+
+impl ChunkStore {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<ChunkStore> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        return Ok(ChunkStore { dir, regions: Mutex::new(HashMap::new()) });
+    }
+
+    // runs f on the region file, or returns None if it doesn't exist and create is false
+    fn with_region<T>(&self, region: (i32, i32, i32), create: bool, f: impl FnOnce(&mut RegionFile) -> io::Result<T>) -> io::Result<Option<T>> {
+        let mut regions = self.regions.lock().unwrap();
+        if !regions.contains_key(&region) {
+            let path = self.dir.join(format!("r.{}.{}.{}.region", region.0, region.1, region.2));
+            if !create && !path.exists() {
+                return Ok(None);
+            }
+            regions.insert(region, RegionFile::open(&path)?);
+        }
+        return f(regions.get_mut(&region).unwrap()).map(Some);
+    }
+
+    pub fn load(&self, coords: (i32, i32, i32)) -> io::Result<Option<Chunk>> {
+        let (region, local) = region_coords(coords);
+        let data = self.with_region(region, false, |r| r.read(local))?.flatten();
+        return data.map(|d| decode_chunk(&d)).transpose();
+    }
+
+    pub fn save(&self, coords: (i32, i32, i32), chunk: &Chunk) -> io::Result<()> {
+        let (region, local) = region_coords(coords);
+        let data = encode_chunk(chunk);
+        self.with_region(region, true, |r| r.write(local, &data))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    fn test_dir(name: &str) -> PathBuf {
+        return temp_dir("region", name);
+    }
+
+    fn striped_chunk(seed: u16) -> Chunk {
+        let mut blocks = [Block::AIR; CHUNK_VOLUME];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            // long runs, short runs and a run longer than u16::MAX at the end
+            *block = if i > 60000 { Block::AIR } else { Block::new((i as u16 / (1 + seed % 7)) % 5 + seed) };
+        }
+        return Chunk::from_blocks(&blocks);
+    }
+
+    fn same_blocks(a: &Chunk, b: &Chunk) -> bool {
+        return (0..CHUNK_VOLUME).all(|i| a.get(i) == b.get(i));
+    }
+
+    #[test]
+    fn chunks_survive_encoding() {
+        for chunk in [striped_chunk(1), striped_chunk(30), Chunk::filled(Block::AIR), Chunk::filled(Block::new(u16::MAX))] {
+            let decoded = decode_chunk(&encode_chunk(&chunk)).unwrap();
+            assert!(same_blocks(&chunk, &decoded));
+            assert_eq!(decoded.palette().len(), chunk.palette().len());
+        }
+        // one run per u16::MAX blocks
+        assert_eq!(encode_chunk(&Chunk::filled(Block::AIR)).len(), 4 + 2 + CHUNK_VOLUME.div_ceil(u16::MAX as usize) * 4);
+    }
+
+    #[test]
+    fn broken_payloads_are_rejected() {
+        let data = encode_chunk(&striped_chunk(3));
+        assert!(decode_chunk(&data[..data.len() - 2]).is_err());
+        assert!(decode_chunk(&[0, 0, 0, 0]).is_err());
+
+        // a palette index past the end of the palette
+        let mut bad_index = encode_chunk(&Chunk::filled(Block::new(4)));
+        let last = bad_index.len() - 2;
+        bad_index[last] = 9;
+        assert!(decode_chunk(&bad_index).is_err());
+    }
+
+    #[test]
+    fn chunks_survive_reopening_the_store() {
+        let dir = test_dir("reopen");
+        let coords = [(0, -1, 0), (-1, -1, -1), (31, 0, 32), (-33, -2, 5)];
+        {
+            let store = ChunkStore::new(&dir).unwrap();
+            for (i, c) in coords.into_iter().enumerate() {
+                store.save(c, &striped_chunk(i as u16)).unwrap();
+            }
+            assert!(store.load((5, 5, 5)).unwrap().is_none());
+        }
+
+        let store = ChunkStore::new(&dir).unwrap();
+        for (i, c) in coords.into_iter().enumerate() {
+            assert!(same_blocks(&store.load(c).unwrap().unwrap(), &striped_chunk(i as u16)));
+        }
+        // every coords is in its own region
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rewriting_chunks_reuses_their_space() {
+        let dir = test_dir("reuse");
+        let path = dir.join("r.region");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut region = RegionFile::open(&path).unwrap();
+
+        let payloads: Vec<Vec<u8>> = (0..8).map(|i| encode_chunk(&striped_chunk(i))).collect();
+        let largest = payloads.iter().map(|p| p.len() as u64).max().unwrap();
+        for round in 0..50 {
+            for local in 0..4 {
+                region.write(local, &payloads[(round + local) % payloads.len()]).unwrap();
+            }
+        }
+        // the four live payloads and at most as much again for the ones they replaced
+        assert!(region.len <= HEADER_SIZE + 8 * largest, "region grew to {}", region.len);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), region.len);
+
+        let mut reopened = RegionFile::open(&path).unwrap();
+        for local in 0..4 {
+            assert_eq!(reopened.read(local).unwrap().unwrap(), payloads[(49 + local) % payloads.len()]);
+        }
+        assert_eq!(reopened.free, region.free);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let dir = test_dir("header");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("r.region");
+        RegionFile::open(&path).unwrap().write(7, &encode_chunk(&striped_chunk(2))).unwrap();
+
+        let original = std::fs::read(&path).unwrap();
+        assert_eq!(&original[0..4], MAGIC);
+
+        let mut newer = original.clone();
+        newer[4..8].copy_from_slice(&(REGION_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &newer).unwrap();
+        let error = RegionFile::open(&path).err().unwrap();
+        assert!(error.to_string().contains("unsupported region version"), "{error}");
+
+        let mut not_region = original.clone();
+        not_region[0..4].copy_from_slice(b"PNG!");
+        std::fs::write(&path, &not_region).unwrap();
+        assert!(RegionFile::open(&path).is_err());
+
+        std::fs::write(&path, &original[..100]).unwrap();
+        assert!(RegionFile::open(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_offsets_fail_only_their_chunk() {
+        let dir = test_dir("offset");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("r.region");
+        let payload = encode_chunk(&striped_chunk(5));
+        {
+            let mut region = RegionFile::open(&path).unwrap();
+            region.write(1, &payload).unwrap();
+            region.write(2, &payload).unwrap();
+        }
+
+        // point chunk 2 past the end of the file
+        let mut bytes = std::fs::read(&path).unwrap();
+        let entry = 8 + 2 * 8;
+        bytes[entry..entry + 4].copy_from_slice(&(u32::MAX - 10).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut region = RegionFile::open(&path).unwrap();
+        assert_eq!(region.read(1).unwrap().unwrap(), payload);
+        assert!(region.read(2).is_err());
+        // saving the chunk again repairs it, and the space it used to have is handed out again
+        region.write(2, &payload).unwrap();
+        assert_eq!(region.read(2).unwrap().unwrap(), payload);
+        assert_eq!(region.len, HEADER_SIZE + 2 * payload.len() as u64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,98 +1,344 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::chunk::{Block, CHUNK_VOLUME, ChunkMesh, Generator, Neighbours, mesh};
-
-type ChunkCache = Arc<Mutex<HashMap<(i32, i32, i32), Arc<[Block; CHUNK_VOLUME]>>>>;
+use super::{ChunkCache, ChunkStore, Job, JobQueue, LoadFocus};
+use crate::chunk::{BlockRegistry, CHUNK_SIDE, Chunk, ChunkMesh, Generator, MeshingMode, Neighbours, mesh, neighbour_index, neighbour_offsets};
 
+#[derive(Clone, Copy)]
 pub struct WorkItem {
     pub coords: (i32, i32, i32),
+    // the World epoch of the chunk when the job was created
+    pub epoch: u64,
 }
 
 pub struct WorkResult {
     pub coords: (i32, i32, i32),
+    pub epoch: u64,
     pub mesh: ChunkMesh,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct PoolStats {
+    pub workers: usize,
+    // jobs ready to run
+    pub queued: usize,
+    // mesh jobs still waiting on generation
+    pub waiting: usize,
+    // finished jobs per second since the previous call to stats
+    pub jobs_per_sec: f64,
+    pub avg_generate_time: Duration,
+    pub avg_mesh_time: Duration,
+}
+
+// totals over the lifetime of the pool, updated by the workers
+#[derive(Default)]
+struct Timings {
+    generated: AtomicU64,
+    generate_nanos: AtomicU64,
+    meshed: AtomicU64,
+    mesh_nanos: AtomicU64,
+}
+
+impl Timings {
+    fn average(count: &AtomicU64, nanos: &AtomicU64) -> Duration {
+        let count = count.load(Ordering::Relaxed);
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        return Duration::from_nanos(nanos.load(Ordering::Relaxed) / count);
+    }
+}
+
+// the chunk and the 26 around it, in neighbour_index order
+fn mesh_inputs(coords: (i32, i32, i32)) -> [(i32, i32, i32); 27] {
+    return neighbour_offsets().map(|(dx, dy, dz)| (coords.0 + dx, coords.1 + dy, coords.2 + dz));
+}
+
+// a mesh job waiting for some of its chunks to be generated
+struct Waiting {
+    item: WorkItem,
+    missing: u32,
+    // tells this wait apart from older ones for the same chunk in `dependents`
+    id: u64,
+}
+
+struct JobState {
+    queue: JobQueue,
+    // newest epoch submitted for every chunk that still has to be meshed
+    live: HashMap<(i32, i32, i32), u64>,
+    // chunks queued for or in the middle of generation, so each one is only generated once
+    generating: HashSet<(i32, i32, i32)>,
+    waiting: HashMap<(i32, i32, i32), Waiting>,
+    // mesh jobs to wake up once a chunk is generated
+    dependents: HashMap<(i32, i32, i32), Vec<((i32, i32, i32), u64)>>,
+    next_wait_id: u64,
+    closed: bool,
+}
+
+impl JobState {
+    // queues the mesh job if every chunk it reads is in the cache, otherwise generates the missing ones first.
+    // the chunks stay pinned until the mesh job is done or dropped
+    fn schedule(&mut self, cache: &ChunkCache, item: WorkItem) {
+        if let Some(waiting) = self.waiting.get_mut(&item.coords) {
+            waiting.item = item;
+            return;
+        }
+
+        let id = self.next_wait_id;
+        self.next_wait_id += 1;
+
+        let mut missing = 0;
+        for coords in mesh_inputs(item.coords) {
+            cache.pin(coords);
+            if cache.contains(coords) {
+                continue;
+            }
+            missing += 1;
+            self.dependents.entry(coords).or_default().push((item.coords, id));
+            if self.generating.insert(coords) {
+                self.queue.push(Job::Generate(coords));
+            }
+        }
+
+        if missing == 0 {
+            self.queue.push(Job::Mesh(item));
+        } else {
+            self.waiting.insert(item.coords, Waiting { item, missing, id });
+        }
+    }
+
+    // wakes up the mesh jobs that were only waiting on this chunk
+    fn generated(&mut self, coords: (i32, i32, i32)) {
+        self.generating.remove(&coords);
+        for (dependent, id) in self.dependents.remove(&coords).unwrap_or_default() {
+            let Some(waiting) = self.waiting.get_mut(&dependent) else {
+                continue;
+            };
+            if waiting.id != id {
+                continue;
+            }
+            waiting.missing -= 1;
+            if waiting.missing == 0 {
+                let waiting = self.waiting.remove(&dependent).unwrap();
+                self.queue.push(Job::Mesh(waiting.item));
+            }
+        }
+    }
+
+    // false once every mesh job that needed the chunk was cancelled
+    fn is_wanted(&self, coords: (i32, i32, i32)) -> bool {
+        return self.dependents.get(&coords).is_some_and(|d| d.iter().any(|(dependent, id)| self.waiting.get(dependent).is_some_and(|w| w.id == *id)));
+    }
+}
+
+// every worker takes the most important job from the same queue, so a slow job never holds up the others
+struct SharedJobs {
+    state: Mutex<JobState>,
+    available: Condvar,
+    cache: Arc<ChunkCache>,
+    timings: Timings,
+}
+
+impl SharedJobs {
+    // blocks until there is a job, returns None once the pool is shutting down.
+    // jobs that were cancelled or replaced by a newer submit are skipped
+    fn next(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(job) = state.queue.pop() {
+                match job {
+                    Job::Generate(coords) if !state.is_wanted(coords) => {
+                        state.generating.remove(&coords);
+                        state.dependents.remove(&coords);
+                        continue;
+                    }
+                    Job::Mesh(item) if state.live.get(&item.coords) != Some(&item.epoch) => {
+                        // unpinning can save evicted chunks, other threads shouldn't wait on that
+                        drop(state);
+                        self.unpin_inputs(item.coords);
+                        state = self.state.lock().unwrap();
+                        continue;
+                    }
+                    _ => return Some(job),
+                }
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    // runs f on the job state and wakes a worker for every job it queued
+    fn update(&self, f: impl FnOnce(&mut JobState)) {
+        let mut state = self.state.lock().unwrap();
+        let queued = state.queue.len();
+        f(&mut state);
+        let added = state.queue.len().saturating_sub(queued);
+        drop(state);
+        for _ in 0..added {
+            self.available.notify_one();
+        }
+    }
+
+    fn unpin_inputs(&self, coords: (i32, i32, i32)) {
+        for coords in mesh_inputs(coords) {
+            self.cache.unpin(coords);
+        }
+    }
+}
+
 pub struct WorkerPool {
-    senders: Vec<mpsc::Sender<WorkItem>>,
+    jobs: Arc<SharedJobs>,
     handles: Vec<JoinHandle<()>>,
     receiver: mpsc::Receiver<WorkResult>,
-    next_worker: usize,
+    // when stats was last called and how many jobs were finished by then
+    last_sample: (Instant, u64),
 }
 
 impl WorkerPool {
-    pub fn new(num_workers: usize, seed: u32, cache: ChunkCache) -> Self {
-        let mut senders: Vec<mpsc::Sender<WorkItem>> = Vec::with_capacity(num_workers);
+    // num_workers defaults to one per core
+    pub fn new(num_workers: Option<usize>, generator: Arc<dyn Generator>, meshing_mode: MeshingMode, focus: LoadFocus, registry: Arc<BlockRegistry>, store: Arc<ChunkStore>, cache: Arc<ChunkCache>) -> Self {
+        let num_workers = num_workers.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
         let mut handles = Vec::with_capacity(num_workers);
         let (result_sender, receiver) = mpsc::channel();
-        let side = 32;
+        let side = CHUNK_SIDE as i32;
 
-        for _ in 0..num_workers {
-            let (tx, rx) = mpsc::channel();
-            senders.push(tx);
+        let jobs = Arc::new(SharedJobs {
+            state: Mutex::new(JobState {
+                queue: JobQueue::new(focus),
+                live: HashMap::new(),
+                generating: HashSet::new(),
+                waiting: HashMap::new(),
+                dependents: HashMap::new(),
+                next_wait_id: 0,
+                closed: false,
+            }),
+            available: Condvar::new(),
+            cache: cache.clone(),
+            timings: Timings::default(),
+        });
 
+        for _ in 0..num_workers {
+            let jobs = jobs.clone();
             let result_sender = result_sender.clone();
-            let cache_clone = cache.clone();
+            let cache = cache.clone();
+            let registry = registry.clone();
+            let store = store.clone();
+            let generator = generator.clone();
             let handle = thread::spawn(move || {
-                let generator = Generator::new(seed);
-                while let Ok(item) = rx.recv() {
-                    ///////// BADDD
-                    let get = |cx: i32, cy: i32, cz: i32| -> Arc<[Block; CHUNK_VOLUME]> {
-                        {
-                            let map = cache_clone.lock().unwrap();
-                            if let Some(b) = map.get(&(cx, cy, cz)) {
-                                return b.clone();
+                while let Some(job) = jobs.next() {
+                    match job {
+                        Job::Generate(coords) => {
+                            let start = Instant::now();
+                            let chunk = match store.load(coords) {
+                                Ok(Some(chunk)) => chunk,
+                                result => {
+                                    if let Err(e) = result {
+                                        eprintln!("Failed to load chunk {coords:?}, regenerating: {e}");
+                                    }
+                                    // generation is deterministic, only chunks that were edited since get saved
+                                    Chunk::from_blocks(&generator.generate_blocks(coords.0 * side, coords.1 * side, coords.2 * side))
+                                }
+                            };
+                            cache.insert(coords, chunk);
+                            jobs.timings.generated.fetch_add(1, Ordering::Relaxed);
+                            jobs.timings.generate_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                            jobs.update(|state| state.generated(coords));
+                        }
+                        Job::Mesh(item) => {
+                            let start = Instant::now();
+                            let inputs = mesh_inputs(item.coords).map(|c| cache.get(c).expect("mesh inputs are pinned"));
+                            let neighbours = Neighbours::new(std::array::from_fn(|i| Some(&*inputs[i])));
+                            let chunk_mesh = mesh(&inputs[neighbour_index(0, 0, 0)], neighbours, &registry, meshing_mode);
+                            jobs.timings.meshed.fetch_add(1, Ordering::Relaxed);
+                            jobs.timings.mesh_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+                            jobs.unpin_inputs(item.coords);
+                            jobs.update(|state| {
+                                if state.live.get(&item.coords) == Some(&item.epoch) {
+                                    state.live.remove(&item.coords);
+                                }
+                            });
+
+                            if result_sender
+                                .send(WorkResult {
+                                    coords: item.coords,
+                                    epoch: item.epoch,
+                                    mesh: chunk_mesh,
+                                })
+                                .is_err()
+                            {
+                                break;
                             }
                         }
-                        let blocks = Arc::new(generator.generate_blocks(cx * side, cy * side, cz * side));
-                        let mut map = cache_clone.lock().unwrap();
-                        map.entry((cx, cy, cz)).or_insert(blocks).clone()
-                    };
-
-                    let cx = item.coords.0;
-                    let cy = item.coords.1;
-                    let cz = item.coords.2;
-
-                    let blocks = get(cx, cy, cz);
-                    let xp = get(cx + 1, cy, cz);
-                    let xn = get(cx - 1, cy, cz);
-                    let yp = get(cx, cy + 1, cz);
-                    let yn = get(cx, cy - 1, cz);
-                    let zp = get(cx, cy, cz + 1);
-                    let zn = get(cx, cy, cz - 1);
-
-                    let chunk_mesh = mesh(
-                        &blocks,
-                        Neighbours {
-                            xp: Some(&xp),
-                            xn: Some(&xn),
-                            yp: Some(&yp),
-                            yn: Some(&yn),
-                            zp: Some(&zp),
-                            zn: Some(&zn),
-                        },
-                    );
-                    if result_sender.send(WorkResult { coords: item.coords, mesh: chunk_mesh }).is_err() {
-                        break;
                     }
                 }
             });
             handles.push(handle);
         }
 
-        return WorkerPool { senders, handles, receiver, next_worker: 0 };
+        return WorkerPool {
+            jobs,
+            handles,
+            receiver,
+            last_sample: (Instant::now(), 0),
+        };
     }
 
+    // replaces any job still queued for the same chunk
     pub fn submit(&mut self, item: WorkItem) {
-        if let Some(sender) = self.senders.get(self.next_worker) {
-            let _ = sender.send(item);
-            self.next_worker = (self.next_worker + 1) % self.senders.len();
+        let cache = self.jobs.cache.clone();
+        self.jobs.update(|state| {
+            state.live.insert(item.coords, item.epoch);
+            state.schedule(&cache, item);
+        });
+    }
+
+    // queued jobs for the chunk are skipped, one that already started still sends a result
+    pub fn cancel(&mut self, coords: (i32, i32, i32)) {
+        let was_waiting = {
+            let mut state = self.jobs.state.lock().unwrap();
+            state.live.remove(&coords);
+            state.waiting.remove(&coords).is_some()
+        };
+        if was_waiting {
+            self.jobs.unpin_inputs(coords);
         }
     }
 
+    // reorders every queued job around the new focus
+    pub fn set_focus(&mut self, focus: LoadFocus) {
+        self.jobs.state.lock().unwrap().queue.set_focus(focus);
+    }
+
+    pub fn stats(&mut self) -> PoolStats {
+        let (queued, waiting) = {
+            let state = self.jobs.state.lock().unwrap();
+            (state.queue.len(), state.waiting.len())
+        };
+
+        let timings = &self.jobs.timings;
+        let finished = timings.generated.load(Ordering::Relaxed) + timings.meshed.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sample.0).as_secs_f64();
+        let jobs_per_sec = if elapsed > 0.0 { (finished - self.last_sample.1) as f64 / elapsed } else { 0.0 };
+        self.last_sample = (now, finished);
+
+        return PoolStats {
+            workers: self.handles.len(),
+            queued,
+            waiting,
+            jobs_per_sec,
+            avg_generate_time: Timings::average(&timings.generated, &timings.generate_nanos),
+            avg_mesh_time: Timings::average(&timings.meshed, &timings.mesh_nanos),
+        };
+    }
+
     pub fn try_recv(&self) -> Option<WorkResult> {
         return self.receiver.try_recv().ok();
     }
@@ -100,9 +346,131 @@ impl WorkerPool {
 
 impl Drop for WorkerPool {
     fn drop(&mut self) {
-        self.senders.clear();
+        self.jobs.state.lock().unwrap().closed = true;
+        self.jobs.available.notify_all();
         for handle in self.handles.drain(..) {
             let _ = handle.join();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Biome, Block, CHUNK_VOLUME};
+    use crate::test_utils::{registry, temp_dir};
+
+    // stone below y = 0, holds every generate job until it is opened
+    struct GatedGenerator {
+        stone: Block,
+        open: Mutex<bool>,
+        opened: Condvar,
+    }
+
+    impl GatedGenerator {
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    impl Generator for GatedGenerator {
+        fn sample_height(&self, _x: i32, _z: i32) -> i32 {
+            return 0;
+        }
+
+        fn biome_at(&self, _x: i32, _z: i32) -> Biome {
+            return Biome::Plains;
+        }
+
+        fn generate_blocks(&self, _x: i32, y: i32, _z: i32) -> [Block; CHUNK_VOLUME] {
+            let _open = self.opened.wait_while(self.open.lock().unwrap(), |open| !*open).unwrap();
+            return [if y < 0 { self.stone } else { Block::AIR }; CHUNK_VOLUME];
+        }
+    }
+
+    fn test_pool(name: &str, num_workers: Option<usize>, open: bool) -> (WorkerPool, Arc<GatedGenerator>, std::path::PathBuf) {
+        let registry = registry();
+        let generator = Arc::new(GatedGenerator {
+            stone: registry.get("stone").unwrap(),
+            open: Mutex::new(open),
+            opened: Condvar::new(),
+        });
+        let dir = temp_dir("pool", name);
+        let store = Arc::new(ChunkStore::new(&dir).unwrap());
+        let cache = Arc::new(ChunkCache::new(store.clone(), 0));
+        let focus = LoadFocus { center: (0, 0, 0), frustum: None };
+        let pool = WorkerPool::new(num_workers, generator.clone(), MeshingMode::Greedy, focus, Arc::new(registry), store, cache);
+        return (pool, generator, dir);
+    }
+
+    // waits for count results, panics if they take too long
+    fn receive(pool: &WorkerPool, count: usize) -> Vec<WorkResult> {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut results = Vec::new();
+        while results.len() < count {
+            assert!(Instant::now() < deadline, "only {} of {count} jobs finished", results.len());
+            match pool.try_recv() {
+                Some(result) => results.push(result),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        return results;
+    }
+
+    #[test]
+    fn every_job_finishes_with_more_jobs_than_workers() {
+        let (mut pool, _, dir) = test_pool("all", Some(2), true);
+        let coords = |i: i32| (i % 5, i / 5 % 2 - 1, i / 10);
+        let items: Vec<WorkItem> = (0..40).map(|i| WorkItem { coords: coords(i), epoch: i as u64 }).collect();
+        for &item in &items {
+            pool.submit(item);
+        }
+
+        let mut results: Vec<((i32, i32, i32), u64)> = receive(&pool, items.len()).iter().map(|r| (r.coords, r.epoch)).collect();
+        results.sort_unstable();
+        let mut expected: Vec<((i32, i32, i32), u64)> = items.iter().map(|i| (i.coords, i.epoch)).collect();
+        expected.sort_unstable();
+        assert_eq!(results, expected);
+        assert!(pool.try_recv().is_none());
+        drop(pool);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_default_pool_has_a_worker_per_core() {
+        let (mut pool, _, dir) = test_pool("default", None, true);
+        let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        assert_eq!(pool.stats().workers, cores);
+        drop(pool);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stats_follow_the_jobs() {
+        let (mut pool, generator, dir) = test_pool("stats", Some(1), false);
+        let before = pool.stats();
+        assert_eq!((before.queued, before.waiting), (0, 0));
+        assert_eq!(before.avg_generate_time, Duration::ZERO);
+
+        for x in 0..3 {
+            pool.submit(WorkItem { coords: (x, -1, 0), epoch: 1 });
+        }
+        // the worker is stuck on the first generate job, the rest are queued behind it
+        let blocked = pool.stats();
+        assert_eq!(blocked.waiting, 3);
+        assert!(blocked.queued > 0);
+        assert_eq!(blocked.jobs_per_sec, 0.0);
+
+        generator.open();
+        let results = receive(&pool, 3);
+        assert!(results.iter().all(|r| !r.mesh.faces.is_empty()));
+        let done = pool.stats();
+        assert_eq!((done.queued, done.waiting), (0, 0));
+        assert!(done.jobs_per_sec > 0.0);
+        assert!(done.avg_generate_time > Duration::ZERO);
+        assert!(done.avg_mesh_time > Duration::ZERO);
+        drop(pool);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}